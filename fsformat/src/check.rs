@@ -0,0 +1,304 @@
+use std::collections::HashSet;
+
+use crate::disk::join_path;
+use crate::fs::{
+    File, FileType, BLOCK_SIZE, CRC_PER_BLOCK, DIRECT_PTR_CNT, FEATURES_KNOWN, FEATURE_SHARED_DATA,
+    FEATURE_SPARSE, FEATURE_SYMLINK, FS_MAGIC, FS_VERSION, INDIRECT_PTR_CNT, MAX_NAME_LEN,
+    MAX_PATH_LEN, MODE_MASK,
+};
+use crate::image::Image;
+
+const RESERVED: &str = "<reserved>";
+const CHECKSUMS: &str = "<checksums>";
+
+/// Consistency checker for a disk image.
+/// Walks the tree from `s_root` and cross-checks every referenced block against the bitmap.
+pub struct Checker<'a> {
+    image: &'a Image,
+    block_cnt: u32,
+    owners: Vec<Option<String>>,
+    /// Pointer sets of the regular files checked so far, for images where identical
+    /// files share their blocks
+    shared: HashSet<Vec<u32>>,
+    problems: Vec<String>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(image: &'a Image) -> Self {
+        Self {
+            image,
+            block_cnt: 0,
+            owners: Vec::new(),
+            shared: HashSet::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// Run every check and return the problems found, in discovery order
+    pub fn check(mut self) -> Vec<String> {
+        let super_block = &self.image.super_block;
+        if super_block.get_magic() != FS_MAGIC {
+            self.problems.push(format!(
+                "bad magic 0x{:08x} in superblock, expected 0x{:08x}",
+                super_block.get_magic(),
+                FS_MAGIC
+            ));
+            return self.problems;
+        }
+
+        if super_block.get_version() > FS_VERSION {
+            self.problems.push(format!(
+                "unsupported format version {}, expected at most {}",
+                super_block.get_version(),
+                FS_VERSION
+            ));
+            return self.problems;
+        }
+        if super_block.get_features() & !FEATURES_KNOWN != 0 {
+            self.problems.push(format!(
+                "unknown feature flags 0x{:x} in superblock",
+                super_block.get_features() & !FEATURES_KNOWN
+            ));
+        }
+
+        if super_block.get_boot_cnt() != self.image.super_block_no() {
+            self.problems.push(format!(
+                "superblock in block {} records a boot area of {} blocks",
+                self.image.super_block_no(),
+                super_block.get_boot_cnt()
+            ));
+            return self.problems;
+        }
+
+        self.block_cnt = super_block.get_block_cnt();
+        let meta_cnt = super_block.bitmap_start() + self.image.bitmap_block_cnt();
+        if self.block_cnt > self.image.block_count() {
+            self.problems.push(format!(
+                "superblock claims {} blocks but the image only holds {}",
+                self.block_cnt,
+                self.image.block_count()
+            ));
+            self.block_cnt = self.image.block_count();
+        }
+        if self.block_cnt < meta_cnt {
+            self.problems.push(format!(
+                "block count {} is too small to hold the superblock and bitmap",
+                self.block_cnt
+            ));
+            return self.problems;
+        }
+
+        self.owners = vec![None; self.block_cnt as usize];
+        for i in 0..meta_cnt {
+            self.claim(i, RESERVED);
+        }
+        if let Some(blocks) = super_block.checksum_blocks() {
+            let needed = super_block.get_block_cnt().div_ceil(CRC_PER_BLOCK);
+            if blocks.end > self.block_cnt || (blocks.len() as u32) < needed {
+                self.problems.push(format!(
+                    "checksum table of {} blocks from block {} does not fit or cover {} blocks",
+                    blocks.len(),
+                    blocks.start,
+                    super_block.get_block_cnt()
+                ));
+            } else {
+                for i in blocks {
+                    self.claim(i, CHECKSUMS);
+                }
+            }
+        }
+
+        let root = self.image.super_block.s_root.clone();
+        if root.get_type() != FileType::Directory {
+            self.problems.push("root is not a directory".to_string());
+        }
+        self.check_file(&root, "/");
+
+        for i in 0..self.block_cnt {
+            if self.owners[i as usize].is_none() && !self.image.is_free(i) {
+                self.problems
+                    .push(format!("block {}: marked used but not referenced", i));
+            }
+        }
+        self.problems
+    }
+
+    /// Record that `path` uses block `n`. Returns false if the block was already taken.
+    fn claim(&mut self, n: u32, path: &str) -> bool {
+        if self.image.is_free(n) {
+            self.problems
+                .push(format!("block {}: marked free but used by '{}'", n, path));
+        }
+        match &self.owners[n as usize] {
+            Some(owner) => {
+                self.problems.push(format!(
+                    "block {}: used by both '{}' and '{}'",
+                    n, owner, path
+                ));
+                false
+            }
+            None => {
+                self.owners[n as usize] = Some(path.to_string());
+                true
+            }
+        }
+    }
+
+    /// Check a pointer stored in `path` and claim the block it refers to.
+    /// A `shared` pointer was claimed already by a file with the same pointers.
+    fn claim_pointer(&mut self, n: u32, path: &str, shared: bool) -> bool {
+        if n >= self.block_cnt {
+            self.problems.push(format!(
+                "'{}': pointer to block {} past the end of the disk ({} blocks)",
+                path, n, self.block_cnt
+            ));
+            return false;
+        }
+        shared || self.claim(n, path)
+    }
+
+    fn check_file(&mut self, file: &File, path: &str) {
+        // A file may only share blocks with one that points at exactly the same ones,
+        // so each block keeps a single role
+        let pointers = file.pointers();
+        let shared = file.get_type() == FileType::File
+            && self.image.super_block.has_feature(FEATURE_SHARED_DATA)
+            && pointers.iter().any(|&n| n != 0)
+            && !self.shared.insert(pointers);
+        let mut blocks = Vec::new();
+        for i in 0..DIRECT_PTR_CNT {
+            blocks.push(file.get_direct(i));
+        }
+        let indirect = file.get_indirect();
+        let valid = indirect != 0 && self.claim_pointer(indirect, path, shared);
+        for i in DIRECT_PTR_CNT..INDIRECT_PTR_CNT {
+            blocks.push(if valid {
+                self.image.read_u32(indirect, i)
+            } else {
+                0
+            });
+        }
+        let dindirect = file.get_dindirect();
+        if dindirect != 0 && !self.image.double_indirect() {
+            self.problems.push(format!(
+                "'{}': has a double-indirect block but the image does not use that layout",
+                path
+            ));
+        } else if dindirect != 0 && self.claim_pointer(dindirect, path, shared) {
+            for i in 0..INDIRECT_PTR_CNT {
+                let index = self.image.read_u32(dindirect, i);
+                let valid = index != 0 && self.claim_pointer(index, path, shared);
+                for j in 0..INDIRECT_PTR_CNT {
+                    blocks.push(if valid {
+                        self.image.read_u32(index, j)
+                    } else {
+                        0
+                    });
+                }
+            }
+        }
+
+        let mut claimed = Vec::new();
+        for (i, &n) in blocks.iter().enumerate() {
+            if n != 0 && self.claim_pointer(n, path, shared) {
+                claimed.push((i as u32, n));
+            }
+        }
+
+        if self.image.super_block.has_metadata() && file.get_mode() & !MODE_MASK != 0 {
+            self.problems.push(format!(
+                "'{}': mode 0{:o} has bits outside 0{:o}",
+                path,
+                file.get_mode(),
+                MODE_MASK
+            ));
+        }
+
+        let used = blocks.iter().filter(|&&n| n != 0).count() as u32;
+        match file.get_type() {
+            FileType::File | FileType::Symlink => {
+                let expected = file.get_size().div_ceil(BLOCK_SIZE);
+                let contiguous = blocks.iter().take(expected as usize).all(|&n| n != 0);
+                let past_end = blocks.iter().skip(expected as usize).any(|&n| n != 0);
+                let holes_allowed = file.get_type() == FileType::File
+                    && self.image.super_block.has_feature(FEATURE_SPARSE);
+                if past_end || (!contiguous && !holes_allowed) {
+                    self.problems.push(format!(
+                        "'{}': f_size {} needs {} blocks but {} are allocated",
+                        path,
+                        file.get_size(),
+                        expected,
+                        used
+                    ));
+                }
+                if file.get_type() == FileType::Symlink {
+                    self.check_symlink(file, path);
+                }
+            }
+            FileType::Directory => {
+                if !file.get_size().is_multiple_of(BLOCK_SIZE) {
+                    self.problems.push(format!(
+                        "'{}': directory f_size {} is not a multiple of the block size",
+                        path,
+                        file.get_size()
+                    ));
+                } else if used != file.get_size() / BLOCK_SIZE {
+                    self.problems.push(format!(
+                        "'{}': f_size {} covers {} blocks but {} are allocated",
+                        path,
+                        file.get_size(),
+                        file.get_size() / BLOCK_SIZE,
+                        used
+                    ));
+                }
+                for (i, n) in claimed {
+                    if i < file.get_size() / BLOCK_SIZE {
+                        self.check_dir_block(n, path);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_symlink(&mut self, file: &File, path: &str) {
+        if !self.image.super_block.has_feature(FEATURE_SYMLINK) {
+            self.problems.push(format!(
+                "'{}': is a symlink but the image does not enable symlinks",
+                path
+            ));
+        }
+        if file.get_size() == 0 || file.get_size() >= MAX_PATH_LEN {
+            self.problems.push(format!(
+                "'{}': symlink target length {} is out of range",
+                path,
+                file.get_size()
+            ));
+        }
+    }
+
+    fn check_dir_block(&mut self, n: u32, dir_path: &str) {
+        let image = self.image;
+        for slot in image.file_slots(n) {
+            if slot[0] == 0 {
+                continue;
+            }
+            if slot[MAX_NAME_LEN as usize - 1] != 0 {
+                self.problems.push(format!(
+                    "block {}: file name in '{}' is not terminated",
+                    n, dir_path
+                ));
+                continue;
+            }
+            match File::from_bytes(slot) {
+                Some(file) => {
+                    let path = join_path(dir_path, &file.get_name());
+                    self.check_file(&file, &path);
+                }
+                None => self.problems.push(format!(
+                    "block {}: entry in '{}' has an invalid type",
+                    n, dir_path
+                )),
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Compare two images file by file.
+//!
+//! Entries are matched by path. For each path the comparison reports a change of type,
+//! of contents, of metadata (when both images record it) or, for identical contents,
+//! of the blocks holding them. Superblock fields are compared first.
+//!
+//! The machine-readable form has one tab-separated line per difference:
+//!
+//! ```text
+//! superblock  <field>  <old>  <new>
+//! added       <path>   <type>
+//! removed     <path>   <type>
+//! type        <path>   <old>  <new>
+//! content     <path>   <old size>  <new size>
+//! metadata    <path>   <field>  <old>  <new>
+//! layout      <path>   <old blocks>  <new blocks>
+//! ```
+//!
+//! Tabs, newlines and backslashes in paths are escaped as `\t`, `\n` and `\\`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use crate::disk::join_path;
+use crate::fs::{File, FileType};
+use crate::image::Image;
+use crate::inspect::{file_blocks, format_blocks};
+
+pub enum Difference {
+    Superblock {
+        field: &'static str,
+        old: String,
+        new: String,
+    },
+    Added {
+        path: String,
+        file_type: FileType,
+    },
+    Removed {
+        path: String,
+        file_type: FileType,
+    },
+    Type {
+        path: String,
+        old: FileType,
+        new: FileType,
+    },
+    Content {
+        path: String,
+        old_size: u32,
+        new_size: u32,
+    },
+    Metadata {
+        path: String,
+        field: &'static str,
+        old: String,
+        new: String,
+    },
+    Layout {
+        path: String,
+        old: String,
+        new: String,
+    },
+}
+
+/// Every difference between `old` and `new`, superblock first and then by path
+pub fn diff(old: &Image, new: &Image) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let (a, b) = (&old.super_block, &new.super_block);
+    let fields = [
+        ("block_count", a.get_block_cnt(), b.get_block_cnt()),
+        ("version", a.get_version(), b.get_version()),
+        ("features", a.get_features(), b.get_features()),
+        ("boot_blocks", a.get_boot_cnt(), b.get_boot_cnt()),
+    ];
+    for (field, old, new) in fields {
+        if old != new {
+            differences.push(Difference::Superblock {
+                field,
+                old: old.to_string(),
+                new: new.to_string(),
+            });
+        }
+    }
+
+    let old_files = walk(old);
+    let new_files = walk(new);
+    for (path, old_file) in &old_files {
+        if !new_files.contains_key(path) {
+            differences.push(Difference::Removed {
+                path: path.clone(),
+                file_type: old_file.get_type(),
+            });
+        }
+    }
+    for (path, new_file) in &new_files {
+        match old_files.get(path) {
+            Some(old_file) => compare(old, old_file, new, new_file, path, &mut differences),
+            None => differences.push(Difference::Added {
+                path: path.clone(),
+                file_type: new_file.get_type(),
+            }),
+        }
+    }
+    differences.sort_by(|x, y| x.path().cmp(y.path()));
+    differences
+}
+
+fn compare(
+    old: &Image,
+    a: &File,
+    new: &Image,
+    b: &File,
+    path: &str,
+    differences: &mut Vec<Difference>,
+) {
+    if a.get_type() != b.get_type() {
+        differences.push(Difference::Type {
+            path: path.to_string(),
+            old: a.get_type(),
+            new: b.get_type(),
+        });
+        return;
+    }
+
+    // A directory's contents are its entries, which are compared by path
+    let same_content = a.get_type() == FileType::Directory || old.read_file(a) == new.read_file(b);
+    if !same_content {
+        differences.push(Difference::Content {
+            path: path.to_string(),
+            old_size: a.get_size(),
+            new_size: b.get_size(),
+        });
+    }
+
+    if old.super_block.has_metadata() && new.super_block.has_metadata() {
+        let fields = [
+            (
+                "mode",
+                format!("0{:o}", a.get_mode()),
+                format!("0{:o}", b.get_mode()),
+            ),
+            ("uid", a.get_uid().to_string(), b.get_uid().to_string()),
+            ("gid", a.get_gid().to_string(), b.get_gid().to_string()),
+            (
+                "mtime",
+                a.get_mtime().to_string(),
+                b.get_mtime().to_string(),
+            ),
+        ];
+        for (field, old, new) in fields {
+            if old != new {
+                differences.push(Difference::Metadata {
+                    path: path.to_string(),
+                    field,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+
+    if same_content {
+        let old_layout = layout(old, a);
+        let new_layout = layout(new, b);
+        if old_layout != new_layout {
+            differences.push(Difference::Layout {
+                path: path.to_string(),
+                old: old_layout,
+                new: new_layout,
+            });
+        }
+    }
+}
+
+/// Data and index blocks of `file`, in the form `ls` prints them
+fn layout(image: &Image, file: &File) -> String {
+    let blocks = format_blocks(&file_blocks(image, file));
+    let index = image.index_blocks(file);
+    match index.is_empty() {
+        true => blocks,
+        false => format!("{} (index {})", blocks, format_blocks(&index)),
+    }
+}
+
+/// Every entry reachable from the root, keyed by full path
+fn walk(image: &Image) -> BTreeMap<String, File> {
+    let mut files = BTreeMap::new();
+    walk_dir(
+        image,
+        &image.super_block.s_root,
+        "/",
+        &mut files,
+        &mut HashSet::new(),
+    );
+    files
+}
+
+fn walk_dir(
+    image: &Image,
+    file: &File,
+    path: &str,
+    files: &mut BTreeMap<String, File>,
+    visited: &mut HashSet<u32>,
+) {
+    files.insert(path.to_string(), file.clone());
+    // A directory block seen before means a corrupt, cyclic tree
+    if file.get_type() != FileType::Directory || !image.visit_dir(file, visited) {
+        return;
+    }
+    for entry in image.read_dir(file) {
+        let entry_path = join_path(path, &entry.get_name());
+        // A corrupt image may list a name twice; keep the first, as lookup does
+        if !files.contains_key(&entry_path) {
+            walk_dir(image, &entry, &entry_path, files, visited);
+        }
+    }
+}
+
+fn type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::File => "file",
+        FileType::Directory => "dir",
+        FileType::Symlink => "symlink",
+    }
+}
+
+fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+impl Difference {
+    /// Path the difference is about, or "" for the superblock
+    pub fn path(&self) -> &str {
+        match self {
+            Difference::Superblock { .. } => "",
+            Difference::Added { path, .. }
+            | Difference::Removed { path, .. }
+            | Difference::Type { path, .. }
+            | Difference::Content { path, .. }
+            | Difference::Metadata { path, .. }
+            | Difference::Layout { path, .. } => path,
+        }
+    }
+
+    /// One tab-separated line, as described in the module documentation
+    pub fn to_machine(&self) -> String {
+        let fields = match self {
+            Difference::Superblock { field, old, new } => {
+                vec!["superblock", field, old.as_str(), new.as_str()]
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect()
+            }
+            Difference::Added { path, file_type } => {
+                vec![
+                    "added".to_string(),
+                    escape(path),
+                    type_name(*file_type).to_string(),
+                ]
+            }
+            Difference::Removed { path, file_type } => {
+                vec![
+                    "removed".to_string(),
+                    escape(path),
+                    type_name(*file_type).to_string(),
+                ]
+            }
+            Difference::Type { path, old, new } => vec![
+                "type".to_string(),
+                escape(path),
+                type_name(*old).to_string(),
+                type_name(*new).to_string(),
+            ],
+            Difference::Content {
+                path,
+                old_size,
+                new_size,
+            } => vec![
+                "content".to_string(),
+                escape(path),
+                old_size.to_string(),
+                new_size.to_string(),
+            ],
+            Difference::Metadata {
+                path,
+                field,
+                old,
+                new,
+            } => vec![
+                "metadata".to_string(),
+                escape(path),
+                field.to_string(),
+                old.clone(),
+                new.clone(),
+            ],
+            Difference::Layout { path, old, new } => {
+                vec!["layout".to_string(), escape(path), old.clone(), new.clone()]
+            }
+        };
+        fields.join("\t")
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Superblock { field, old, new } => {
+                write!(f, "superblock: {} {} -> {}", field, old, new)
+            }
+            Difference::Added { path, file_type } => {
+                write!(f, "added {} {}", type_name(*file_type), path)
+            }
+            Difference::Removed { path, file_type } => {
+                write!(f, "removed {} {}", type_name(*file_type), path)
+            }
+            Difference::Type { path, old, new } => write!(
+                f,
+                "{}: type {} -> {}",
+                path,
+                type_name(*old),
+                type_name(*new)
+            ),
+            Difference::Content {
+                path,
+                old_size,
+                new_size,
+            } => write!(
+                f,
+                "{}: contents differ (size {} -> {})",
+                path, old_size, new_size
+            ),
+            Difference::Metadata {
+                path,
+                field,
+                old,
+                new,
+            } => write!(f, "{}: {} {} -> {}", path, field, old, new),
+            Difference::Layout { path, old, new } => {
+                write!(f, "{}: same contents, blocks {} -> {}", path, old, new)
+            }
+        }
+    }
+}
@@ -1,183 +1,1591 @@
-use std::{io::Write, mem::size_of, ptr::{addr_of, addr_of_mut}};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
 
+use crate::crc::crc32;
+use crate::filter::{parse_ignore_file, Filter, IgnoreFile, IGNORE_FILE};
 use crate::fs::{
-    Block, BlockType, File, FileType, SuperBlock, BLOCK_SIZE, BLOCK_SIZE_BIT, DIRECT_PTR_CNT,
-    FS_MAGIC, INDIRECT_PTR_CNT, MAX_FILE_SIZE, MAX_NAME_LEN,
+    Block, BlockType, File, FileType, SuperBlock, BLOCK_SIZE, BLOCK_SIZE_BIT, CRC_PER_BLOCK,
+    DINDIRECT_PTR_CNT, DIRECT_PTR_CNT, FEATURE_DOUBLE_INDIRECT, FEATURE_SHARED_DATA,
+    FEATURE_SPARSE, FEATURE_SYMLINK, FILE2BLK, FS_MAGIC, INDIRECT_PTR_CNT, MAX_DINDIRECT_FILE_SIZE,
+    MAX_FILE_SIZE, MAX_NAME_LEN, MAX_PATH_LEN,
 };
+use crate::image::Image;
+use crate::plan::{Plan, PlannedFile};
 
-const BLOCK_COUNT: u32 = 0x400;
-static mut DISK: Disk = Disk::new();
+pub const DEFAULT_BLOCK_COUNT: u32 = 0x400;
 
-pub struct Disk {
-    pub super_block: SuperBlock,
-    pub blocks: [Block; BLOCK_COUNT as usize],
-    pub bit_block_cnt: u32,
-    pub next_block: u32,
+/// Failure while writing into the disk, with the host or image path involved
+#[derive(Debug)]
+pub enum DiskError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    NonUtf8Name {
+        path: String,
+    },
+    NameTooLong {
+        path: String,
+    },
+    FileTooLarge {
+        path: String,
+        size: u64,
+        max: u64,
+    },
+    UnsupportedType {
+        path: String,
+    },
+    NoSuchDirectory {
+        path: String,
+    },
+    NotFound {
+        path: String,
+    },
+    AlreadyExists {
+        path: String,
+    },
+    DirectoryNotEmpty {
+        path: String,
+    },
+    BadPath {
+        path: String,
+        reason: &'static str,
+    },
+    BadSymlink {
+        path: String,
+    },
+    TooFull {
+        path: String,
+        used: u32,
+        block_cnt: u32,
+        max_fill: u32,
+    },
+    OutOfBlocks {
+        path: String,
+        block_cnt: u32,
+    },
+    BadManifest {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+    BadArchive {
+        path: String,
+        offset: usize,
+        reason: String,
+    },
+    BootTooLarge {
+        path: String,
+        size: u64,
+        max: u64,
+    },
+    BadPartition {
+        path: String,
+        reason: String,
+    },
 }
 
-impl Disk {
-    pub const fn new() -> Self {
-        Self {
-            super_block: SuperBlock::new(0, 0),
-            blocks: [Block::new(); BLOCK_COUNT as usize],
-            bit_block_cnt: 0,
-            next_block: 0,
+impl DiskError {
+    fn io(path: &str, source: std::io::Error) -> Self {
+        DiskError::Io {
+            path: path.to_string(),
+            source,
         }
     }
-}
 
-fn disk() -> &'static Disk {
-    unsafe {
-        let p = addr_of!(DISK);
-        &*p
+    /// Process exit code for this kind of failure, distinct per variant
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DiskError::Io { .. } => 2,
+            DiskError::NonUtf8Name { .. } => 3,
+            DiskError::NameTooLong { .. } => 4,
+            DiskError::FileTooLarge { .. } => 5,
+            DiskError::UnsupportedType { .. } => 6,
+            DiskError::OutOfBlocks { .. } => 7,
+            DiskError::NoSuchDirectory { .. } => 8,
+            DiskError::BadManifest { .. } => 9,
+            DiskError::BadSymlink { .. } => 10,
+            DiskError::TooFull { .. } => 11,
+            DiskError::NotFound { .. } => 12,
+            DiskError::AlreadyExists { .. } => 13,
+            DiskError::DirectoryNotEmpty { .. } => 14,
+            DiskError::BadPath { .. } => 15,
+            DiskError::BadArchive { .. } => 16,
+            DiskError::BootTooLarge { .. } => 17,
+            DiskError::BadPartition { .. } => 18,
+        }
     }
 }
 
-pub fn disk_mut() -> &'static mut Disk {
-    unsafe { 
-        let p = addr_of_mut!(DISK);
-        &mut *p
+impl std::fmt::Display for DiskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiskError::Io { path, source } => write!(f, "'{}': {}", path, source),
+            DiskError::NonUtf8Name { path } => write!(f, "'{}': name is not valid UTF-8", path),
+            DiskError::NameTooLong { path } => write!(
+                f,
+                "'{}': name is longer than {} bytes",
+                path,
+                MAX_NAME_LEN - 1
+            ),
+            DiskError::FileTooLarge { path, size, max } => write!(
+                f,
+                "'{}': {} bytes exceeds the maximum file size of {} bytes",
+                path, size, max
+            ),
+            DiskError::UnsupportedType { path } => {
+                write!(f, "'{}': not a regular file or directory", path)
+            }
+            DiskError::NoSuchDirectory { path } => {
+                write!(f, "'{}': no such directory in the image", path)
+            }
+            DiskError::NotFound { path } => {
+                write!(f, "'{}': no such file or directory in the image", path)
+            }
+            DiskError::AlreadyExists { path } => write!(f, "'{}': already exists", path),
+            DiskError::DirectoryNotEmpty { path } => write!(f, "'{}': directory not empty", path),
+            DiskError::BadPath { path, reason } => write!(f, "'{}': {}", path, reason),
+            DiskError::OutOfBlocks { path, block_cnt } => write!(
+                f,
+                "'{}': out of free blocks, the disk has {} blocks",
+                path, block_cnt
+            ),
+            DiskError::TooFull {
+                path,
+                used,
+                block_cnt,
+                max_fill,
+            } => write!(
+                f,
+                "'{}': {} of {} blocks used, over the {}% fill limit",
+                path, used, block_cnt, max_fill
+            ),
+            DiskError::BadSymlink { path } => write!(
+                f,
+                "'{}': symlink target must be between 1 and {} bytes",
+                path,
+                MAX_PATH_LEN - 1
+            ),
+            DiskError::BadManifest { path, line, reason } => {
+                write!(f, "{}:{}: {}", path, line, reason)
+            }
+            DiskError::BadArchive {
+                path,
+                offset,
+                reason,
+            } => write!(f, "{}: at byte {}: {}", path, offset, reason),
+            DiskError::BootTooLarge { path, size, max } => write!(
+                f,
+                "'{}': {} bytes do not fit in the {}-byte boot area",
+                path, size, max
+            ),
+            DiskError::BadPartition { path, reason } => write!(f, "'{}': {}", path, reason),
+        }
     }
 }
 
-pub fn init_disk() {
-    disk_mut().blocks[0].set_type(BlockType::Boot);
+impl std::error::Error for DiskError {}
 
-    disk_mut().bit_block_cnt = (BLOCK_COUNT + BLOCK_SIZE_BIT - 1) / BLOCK_SIZE_BIT;
-    disk_mut().next_block = 2 + disk().bit_block_cnt;
+/// Handle to a `File` record: the root in the superblock, or a slot in a directory block
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FileRef {
+    Root,
+    Slot(u32, u32),
+}
+
+/// Mode, owner and mtime of an entry, as recorded from `FS_VERSION_METADATA` on
+#[derive(Clone, Copy)]
+pub struct Attributes {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+}
 
-    for i in 0..disk().bit_block_cnt as usize {
-        disk_mut().blocks[i + 2].set_type(BlockType::BMap);
-        disk_mut().blocks[i + 2].b_data.fill(0xff);
+impl Attributes {
+    /// What entries without a host file get: owned by root, mtime 0
+    pub fn default_for(file_type: FileType) -> Attributes {
+        let mode = match file_type {
+            FileType::File => 0o644,
+            FileType::Directory => 0o755,
+            FileType::Symlink => 0o777,
+        };
+        Attributes {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        }
     }
 
-    if BLOCK_COUNT != BLOCK_SIZE_BIT * disk().bit_block_cnt {
-        let diff = BLOCK_COUNT % BLOCK_SIZE_BIT / 8;
-        for i in diff..BLOCK_SIZE {
-            disk_mut().blocks[disk().bit_block_cnt as usize + 1].b_data[i as usize] = 0x00;
+    pub fn of(file: &File) -> Attributes {
+        Attributes {
+            mode: file.get_mode(),
+            uid: file.get_uid(),
+            gid: file.get_gid(),
+            mtime: file.get_mtime(),
         }
     }
+}
+
+/// Fixed values for the metadata recorded by `DiskImage::enable_metadata`.
+/// Unset fields come from the host file, or from defaults for entries without one.
+#[derive(Clone, Default)]
+pub struct MetadataOverrides {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<u32>,
+}
 
-    disk_mut().blocks[1].set_type(BlockType::Super);
-    disk_mut().super_block = SuperBlock::new(FS_MAGIC, BLOCK_COUNT);
-    let root = &mut disk_mut().super_block.s_root;
-    root.set_name("/");
-    root.set_type(FileType::Directory);
+/// An owned disk image under construction
+pub struct DiskImage {
+    super_block: SuperBlock,
+    blocks: Vec<Block>,
+    /// No block below this one is free
+    next_free: u32,
+    bit_block_cnt: u32,
+    host_order: bool,
+    preserve_symlinks: bool,
+    metadata: Option<MetadataOverrides>,
+    sparse: bool,
+    sparse_saved: u64,
+    filter: Filter,
+    /// `.fsignore` patterns of the host directories being written, outermost first
+    ignores: Vec<IgnoreFile>,
+    dry_run: bool,
+    /// Entries a dry run could not store, reported by `plan`
+    violations: Vec<DiskError>,
+    /// Regular files written so far, by content hash, when deduplicating
+    dedup: Option<HashMap<u64, Vec<FileRef>>>,
+    /// Number of records pointing at each block that more than one record uses
+    shared: HashMap<u32, u32>,
 }
 
-impl Disk {
-    pub fn write_dir(&mut self, dir: &mut File, path: &str) {
-        let host_dir = std::fs::read_dir(path).unwrap();
-        let target = create_file(dir);
-        let file_name = path.split('/').last().unwrap();
-        if file_name.len() >= MAX_NAME_LEN as usize {
-            panic!("Directory name too long");
+impl DiskImage {
+    /// Create an empty file system of `block_count` blocks.
+    /// Panics below 3 blocks, too few for block 0, the superblock and the bitmap.
+    pub fn new(block_count: u32) -> Self {
+        Self::with_boot_area(block_count, 1)
+    }
+
+    /// Create an empty file system whose first `boot_cnt` blocks are kept for a boot
+    /// payload, with the superblock and bitmap right after them.
+    /// Panics if `block_count` leaves no room for the superblock and bitmap.
+    pub fn with_boot_area(block_count: u32, boot_cnt: u32) -> Self {
+        assert!(
+            block_count as u64 > boot_cnt as u64 + block_count.div_ceil(BLOCK_SIZE_BIT) as u64,
+            "{} blocks cannot hold a boot area of {} blocks, the superblock and the bitmap",
+            block_count,
+            boot_cnt
+        );
+        let mut super_block = SuperBlock::new(FS_MAGIC, block_count);
+        super_block.set_boot_cnt(boot_cnt);
+        let bitmap_start = super_block.bitmap_start() as usize;
+        let mut disk = Self {
+            super_block,
+            blocks: vec![Block::new(); block_count as usize],
+            next_free: 0,
+            bit_block_cnt: block_count.div_ceil(BLOCK_SIZE_BIT),
+            host_order: false,
+            preserve_symlinks: false,
+            metadata: None,
+            sparse: false,
+            sparse_saved: 0,
+            filter: Filter::default(),
+            ignores: Vec::new(),
+            dry_run: false,
+            violations: Vec::new(),
+            dedup: None,
+            shared: HashMap::new(),
+        };
+        for i in 0..boot_cnt as usize {
+            disk.blocks[i].set_type(BlockType::Boot);
         }
-        target.set_name(file_name);
-        target.set_type(FileType::Directory);
-        for entry in host_dir {
-            let entry_name = entry.as_ref().unwrap().file_name().into_string().unwrap();
-            if entry_name == "." || entry_name == ".." {
-                continue;
+
+        for i in 0..disk.bit_block_cnt as usize {
+            disk.blocks[bitmap_start + i].set_type(BlockType::BMap);
+            disk.blocks[bitmap_start + i].b_data.fill(0xff);
+        }
+
+        if block_count != BLOCK_SIZE_BIT * disk.bit_block_cnt {
+            let diff = block_count % BLOCK_SIZE_BIT / 8;
+            for i in diff..BLOCK_SIZE {
+                disk.blocks[bitmap_start + disk.bit_block_cnt as usize - 1].b_data[i as usize] =
+                    0x00;
             }
-            let entry_path = format!("{}/{}", path, entry_name);
-            if entry.unwrap().metadata().unwrap().is_dir() {
-                self.write_dir(target, &entry_path);
+        }
+
+        disk.blocks[boot_cnt as usize].set_type(BlockType::Super);
+        let root = &mut disk.super_block.s_root;
+        root.set_name("/");
+        root.set_type(FileType::Directory);
+        disk
+    }
+
+    /// Rebuild a disk from an existing image, to edit it in place.
+    /// Block types are recovered from the bitmap and by walking the tree from `s_root`.
+    pub fn from_image(image: &Image) -> std::io::Result<Self> {
+        let block_count = image.super_block.get_block_cnt();
+        if image.block_count() < block_count {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("image is shorter than its {} blocks", block_count),
+            ));
+        }
+        let mut disk = Self {
+            super_block: image.super_block.clone(),
+            blocks: vec![Block::new(); block_count as usize],
+            next_free: 0,
+            bit_block_cnt: image.bitmap_block_cnt(),
+            host_order: false,
+            preserve_symlinks: false,
+            metadata: image
+                .super_block
+                .has_metadata()
+                .then(MetadataOverrides::default),
+            sparse: false,
+            sparse_saved: 0,
+            filter: Filter::default(),
+            ignores: Vec::new(),
+            dry_run: false,
+            violations: Vec::new(),
+            dedup: None,
+            shared: HashMap::new(),
+        };
+        let boot_cnt = image.super_block.get_boot_cnt();
+        for i in 0..block_count {
+            let block = &mut disk.blocks[i as usize];
+            block.b_data.copy_from_slice(image.block(i));
+            block.set_type(match i {
+                i if i < boot_cnt => BlockType::Boot,
+                i if i == boot_cnt => BlockType::Super,
+                i if i <= boot_cnt + disk.bit_block_cnt => BlockType::BMap,
+                i if image.is_free(i) => BlockType::Free,
+                _ => BlockType::Data,
+            });
+        }
+        if let Some(blocks) = image.super_block.checksum_blocks() {
+            for i in blocks {
+                disk.blocks[i as usize].set_type(BlockType::Checksum);
+            }
+        }
+        disk.load_types(image, &image.super_block.s_root);
+        let mut uses: HashMap<u32, u32> = HashMap::new();
+        disk.count_uses(image, &image.super_block.s_root, &mut uses);
+        disk.shared = uses.into_iter().filter(|&(_, n)| n > 1).collect();
+        Ok(disk)
+    }
+
+    fn load_types(&mut self, image: &Image, file: &File) {
+        for block_number in image.index_blocks(file) {
+            self.blocks[block_number as usize].set_type(BlockType::Index);
+        }
+        if file.get_type() == FileType::Directory {
+            for i in 0..file.get_size() / BLOCK_SIZE {
+                self.blocks[image.file_block(file, i) as usize].set_type(BlockType::File);
+            }
+            for entry in image.read_dir(file) {
+                self.load_types(image, &entry);
+            }
+        }
+    }
+
+    /// Count the records using each data and index block of regular files
+    fn count_uses(&self, image: &Image, file: &File, uses: &mut HashMap<u32, u32>) {
+        match file.get_type() {
+            FileType::File => {
+                let data =
+                    (0..file.get_size().div_ceil(BLOCK_SIZE)).map(|i| image.file_block(file, i));
+                for block_number in data.chain(image.index_blocks(file)) {
+                    if block_number != 0 {
+                        *uses.entry(block_number).or_insert(0) += 1;
+                    }
+                }
+            }
+            FileType::Directory => {
+                for entry in image.read_dir(file) {
+                    self.count_uses(image, &entry, uses);
+                }
+            }
+            FileType::Symlink => {}
+        }
+    }
+
+    /// Rebuild `image` with every block laid out again in directory-tree order:
+    /// each directory's blocks, then the data of its files, then its subdirectories.
+    /// Free space ends up in one run at the end, and directories lose empty blocks.
+    /// With `shrink`, the disk is cut to the smallest size that holds everything.
+    pub fn compact(image: &Image, shrink: bool) -> Result<Self, DiskError> {
+        let disk = Self::compact_to(image, image.super_block.get_block_cnt())?;
+        if !shrink {
+            return Ok(disk);
+        }
+        let used = disk
+            .blocks
+            .iter()
+            .rposition(|b| b.get_type() != BlockType::Free);
+        let meta_cnt = disk.super_block.bitmap_start();
+        let content = used.map_or(0, |n| n as u32 + 1) - meta_cnt - disk.bit_block_cnt;
+        let mut block_count = content + meta_cnt + 1;
+        while block_count < content + meta_cnt + block_count.div_ceil(BLOCK_SIZE_BIT) {
+            block_count += 1;
+        }
+        Self::compact_to(image, block_count)
+    }
+
+    fn compact_to(image: &Image, block_count: u32) -> Result<Self, DiskError> {
+        let boot_cnt = image.super_block.get_boot_cnt();
+        let mut disk = Self::with_boot_area(block_count, boot_cnt);
+        for i in 0..boot_cnt {
+            disk.blocks[i as usize]
+                .b_data
+                .copy_from_slice(image.block(i));
+        }
+        let root = &image.super_block.s_root;
+        if image.super_block.checksum_blocks().is_some() {
+            disk.enable_checksums()?;
+        }
+        let checksum_blocks = disk.super_block.checksum_blocks();
+        disk.super_block = image.super_block.clone();
+        disk.super_block.set_block_cnt(block_count);
+        if let Some(blocks) = checksum_blocks {
+            disk.super_block.set_checksum_blocks(blocks);
+        }
+        disk.super_block.s_root = root.without_data();
+        disk.copy_dir(image, root, FileRef::Root, "", &mut HashMap::new())?;
+        Ok(disk)
+    }
+
+    fn copy_dir(
+        &mut self,
+        image: &Image,
+        dir: &File,
+        target: FileRef,
+        path: &str,
+        copied: &mut HashMap<Vec<u32>, FileRef>,
+    ) -> Result<(), DiskError> {
+        let mut entries = Vec::new();
+        for entry in image.read_dir(dir) {
+            let entry_path = format!("{}/{}", path, entry.get_name());
+            let slot = self
+                .create_file(target)
+                .ok_or_else(|| self.out_of_blocks(&entry_path))?;
+            *self.file_mut(slot) = entry.without_data();
+            entries.push((entry, slot, entry_path));
+        }
+        // Files first, so their data sits right after the directory's blocks
+        let (dirs, files): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(entry, _, _)| entry.get_type() == FileType::Directory);
+        for (entry, slot, entry_path) in files {
+            // Files sharing blocks in the source keep sharing them
+            let pointers = entry.pointers();
+            if entry.get_type() == FileType::File && pointers.iter().any(|&n| n != 0) {
+                if let Some(&first) = copied.get(&pointers) {
+                    self.share_blocks(first, slot);
+                    continue;
+                }
+                copied.insert(pointers, slot);
+            }
+            for i in 0..entry.get_size().div_ceil(BLOCK_SIZE) {
+                let source = image.file_block(&entry, i);
+                if source == 0 {
+                    continue;
+                }
+                let block_number = self
+                    .next_block(BlockType::Data)
+                    .ok_or_else(|| self.out_of_blocks(&entry_path))?;
+                self.blocks[block_number as usize]
+                    .b_data
+                    .copy_from_slice(image.block(source));
+                self.save_block_link(slot, i, block_number)
+                    .ok_or_else(|| self.out_of_blocks(&entry_path))?;
+            }
+            self.file_mut(slot).set_size(entry.get_size());
+        }
+        for (entry, slot, entry_path) in dirs {
+            self.copy_dir(image, &entry, slot, &entry_path, copied)?;
+        }
+        Ok(())
+    }
+
+    /// Total number of blocks on the disk
+    pub fn block_cnt(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    /// Switch to the double-indirect layout, which allows files past `MAX_FILE_SIZE`.
+    /// Existing files stay valid, since the layout only adds `f_dindirect`.
+    pub fn enable_double_indirect(&mut self) {
+        self.super_block.set_feature(FEATURE_DOUBLE_INDIRECT);
+    }
+
+    /// Store host symlinks as `FileType::Symlink` entries instead of copying what they point to
+    pub fn set_preserve_symlinks(&mut self, preserve_symlinks: bool) {
+        self.preserve_symlinks = preserve_symlinks;
+    }
+
+    /// Skip host directory entries that `filter` rejects
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    /// Plan the layout without the disk size as a limit: allocation carries on past the
+    /// last block, and host entries that cannot be stored are recorded instead of failing.
+    /// The result is only meant for `plan`, never for writing.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Keep a CRC-32 of every used block in a table of its own, refreshed on every write.
+    /// The table takes the first run of free blocks that is long enough.
+    pub fn enable_checksums(&mut self) -> Result<(), DiskError> {
+        if self.super_block.checksum_blocks().is_some() {
+            return Ok(());
+        }
+        let len = self.block_cnt().div_ceil(CRC_PER_BLOCK);
+        let start = (0..=self.block_cnt() - len)
+            .find(|&start| {
+                (start..start + len).all(|i| self.blocks[i as usize].get_type() == BlockType::Free)
+            })
+            .ok_or_else(|| self.out_of_blocks("checksum table"))?;
+        for i in start..start + len {
+            self.blocks[i as usize].set_type(BlockType::Checksum);
+        }
+        self.super_block.set_checksum_blocks(start..start + len);
+        Ok(())
+    }
+
+    /// Load a bootloader or kernel into the boot area, starting at block 0
+    pub fn set_boot_payload(&mut self, data: &[u8], path: &str) -> Result<(), DiskError> {
+        let boot_cnt = self.super_block.get_boot_cnt() as usize;
+        let max = boot_cnt as u64 * BLOCK_SIZE as u64;
+        if data.len() as u64 > max {
+            return Err(DiskError::BootTooLarge {
+                path: path.to_string(),
+                size: data.len() as u64,
+                max,
+            });
+        }
+        for block in &mut self.blocks[..boot_cnt] {
+            block.b_data.fill(0);
+        }
+        for (block, chunk) in self.blocks.iter_mut().zip(data.chunks(BLOCK_SIZE as usize)) {
+            block.b_data[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    /// Let regular files with the same contents share their data blocks
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup.then(HashMap::new);
+    }
+
+    /// Leave all-zero chunks of regular files unallocated, as holes with a null pointer.
+    /// The image gets `FEATURE_SPARSE` once the first hole is written.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+
+    /// Bytes of file data left out as holes so far
+    pub fn sparse_saved(&self) -> u64 {
+        self.sparse_saved
+    }
+
+    /// Record mode, owner and mtime in every `File` record, bumping the image to
+    /// `FS_VERSION_METADATA`. Entries written before this call keep zeroed metadata.
+    pub fn enable_metadata(&mut self, overrides: MetadataOverrides) {
+        self.super_block.enable_metadata();
+        self.metadata = Some(overrides);
+        self.stamp(FileRef::Root, None);
+    }
+
+    /// Write host directory entries in the order `read_dir` returns them instead of by name.
+    /// The output then depends on the host file system and is no longer reproducible.
+    pub fn set_host_order(&mut self, host_order: bool) {
+        self.host_order = host_order;
+    }
+
+    /// Copy a host file or directory into the image directory `dir`
+    pub fn add_path(&mut self, dir: &str, host_path: &str) -> Result<(), DiskError> {
+        let dir = self.lookup_dir(dir)?;
+        let result =
+            entry_name(host_path).and_then(|name| self.write_path(dir, name, host_path, ""));
+        self.recover(result)
+    }
+
+    /// Copy a host file or directory into `dir` under a new name
+    pub fn add_path_as(&mut self, dir: &str, name: &str, host_path: &str) -> Result<(), DiskError> {
+        let dir = self.lookup_dir(dir)?;
+        let result =
+            check_name(name, host_path).and_then(|_| self.write_path(dir, name, host_path, ""));
+        self.recover(result)
+    }
+
+    /// Create a regular file named `name` in `dir` holding `data`
+    pub fn add_file(&mut self, dir: &str, name: &str, data: &[u8]) -> Result<(), DiskError> {
+        let path = join_path(dir, name);
+        let dir = self.lookup_dir(dir)?;
+        check_name(name, &path)?;
+        self.write_data(dir, name, data, FileType::File, &path)
+            .map(|_| ())
+    }
+
+    /// Create a symbolic link named `name` in `dir` pointing at `target`
+    pub fn add_symlink(&mut self, dir: &str, name: &str, target: &str) -> Result<(), DiskError> {
+        let path = join_path(dir, name);
+        let dir = self.lookup_dir(dir)?;
+        check_name(name, &path)?;
+        self.write_link(dir, name, target, &path).map(|_| ())
+    }
+
+    /// Create an empty directory named `name` in `dir`, keeping it if it already exists
+    pub fn add_dir(&mut self, dir: &str, name: &str) -> Result<(), DiskError> {
+        let path = join_path(dir, name);
+        let dir = self.lookup_dir(dir)?;
+        check_name(name, &path)?;
+        self.make_dir(dir, name, &path).map(|_| ())
+    }
+
+    /// Create the directory at `path` along with any missing parents
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), DiskError> {
+        let mut dir = FileRef::Root;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            check_name(name, path)?;
+            dir = self.make_dir(dir, name, path)?;
+        }
+        Ok(())
+    }
+
+    /// Create the directory at `path`. With `parents`, missing parents are created
+    /// and an existing directory is accepted, as with `mkdir -p`.
+    pub fn make_directory(&mut self, path: &str, parents: bool) -> Result<(), DiskError> {
+        let names = split_names(path)?;
+        if names.is_empty() && !parents {
+            return Err(DiskError::AlreadyExists {
+                path: path.to_string(),
+            });
+        }
+        let mut dir = FileRef::Root;
+        for (i, &name) in names.iter().enumerate() {
+            let last = i + 1 == names.len();
+            check_name(name, path)?;
+            dir = match self.find_file(dir, name) {
+                Some(file) if self.file(file).get_type() == FileType::Directory => {
+                    if last && !parents {
+                        return Err(DiskError::AlreadyExists {
+                            path: path.to_string(),
+                        });
+                    }
+                    file
+                }
+                Some(_) if last => {
+                    return Err(DiskError::AlreadyExists {
+                        path: path.to_string(),
+                    })
+                }
+                Some(_) => {
+                    return Err(DiskError::NoSuchDirectory {
+                        path: format!("/{}", names[..=i].join("/")),
+                    })
+                }
+                None if last || parents => self.make_dir(dir, name, path)?,
+                None => {
+                    return Err(DiskError::NoSuchDirectory {
+                        path: format!("/{}", names[..=i].join("/")),
+                    })
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Set the mode, owner and mtime of the entry at `path`, subject to the overrides.
+    /// Does nothing unless metadata is enabled.
+    pub fn set_attributes(&mut self, path: &str, attributes: Attributes) -> Result<(), DiskError> {
+        let file = match split_names(path)?.is_empty() {
+            true => FileRef::Root,
+            false => self.lookup_entry(path)?.1,
+        };
+        self.stamp(file, Some(attributes));
+        Ok(())
+    }
+
+    /// Delete the entry at `path` and free its blocks.
+    /// A directory must be empty unless `recursive` is set.
+    pub fn remove(&mut self, path: &str, recursive: bool) -> Result<(), DiskError> {
+        let (dir, file) = self.lookup_entry(path)?;
+        if !recursive
+            && self.file(file).get_type() == FileType::Directory
+            && !self.dir_entries(file).is_empty()
+        {
+            return Err(DiskError::DirectoryNotEmpty {
+                path: path.to_string(),
+            });
+        }
+        self.free_file(file);
+        self.trim_dir(dir);
+        Ok(())
+    }
+
+    /// Move the entry at `from` to `to`, or into `to` if that is a directory.
+    /// An existing regular file at the destination is replaced.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), DiskError> {
+        let (source_dir, source) = self.lookup_entry(from)?;
+        let from_names = split_names(from)?;
+        let mut to_names = split_names(to)?;
+        if self.lookup_dir(to).is_ok() {
+            to_names.push(from_names.last().unwrap());
+        }
+        let to_path = format!("/{}", to_names.join("/"));
+        if to_names == from_names {
+            return Ok(());
+        }
+        if to_names.starts_with(&from_names) {
+            return Err(DiskError::BadPath {
+                path: to.to_string(),
+                reason: "cannot move a directory into itself",
+            });
+        }
+        let (&name, parent) = to_names
+            .split_last()
+            .ok_or_else(|| DiskError::AlreadyExists {
+                path: to_path.clone(),
+            })?;
+        check_name(name, &to_path)?;
+        let target_dir = self.lookup_dir(&format!("/{}", parent.join("/")))?;
+
+        let target = match self.find_file(target_dir, name) {
+            Some(old)
+                if self.file(old).get_type() == FileType::Directory
+                    || self.file(source).get_type() == FileType::Directory =>
+            {
+                return Err(DiskError::AlreadyExists { path: to_path });
+            }
+            Some(old) => {
+                self.free_file(old);
+                old
+            }
+            None => self
+                .create_file(target_dir)
+                .ok_or_else(|| self.out_of_blocks(&to_path))?,
+        };
+        let mut record = self.file(source).clone();
+        record.set_name(name);
+        *self.file_mut(target) = record;
+        *self.file_mut(source) = File::new();
+        self.trim_dir(source_dir);
+        Ok(())
+    }
+
+    /// Layout of every file, blocks needed against the disk size, and the entries
+    /// that could not be stored
+    pub fn plan(&mut self) -> Plan {
+        let mut files = Vec::new();
+        self.plan_file(FileRef::Root, "/", &mut files);
+        Plan {
+            block_cnt: self.super_block.get_block_cnt(),
+            needed: self
+                .blocks
+                .iter()
+                .filter(|block| block.get_type() != BlockType::Free)
+                .count() as u32,
+            files,
+            violations: std::mem::take(&mut self.violations),
+        }
+    }
+
+    fn plan_file(&self, file: FileRef, path: &str, files: &mut Vec<PlannedFile>) {
+        let record = self.file(file);
+        files.push(PlannedFile {
+            path: path.to_string(),
+            file_type: record.get_type(),
+            size: record.get_size(),
+            blocks: (0..record.get_size().div_ceil(BLOCK_SIZE))
+                .map(|i| self.file_block(file, i))
+                .filter(|&n| n != 0)
+                .collect(),
+            index: self.index_blocks(file),
+        });
+        if record.get_type() == FileType::Directory {
+            for entry in self.dir_entries(file) {
+                let entry_path = join_path(path, &self.file(entry).get_name());
+                self.plan_file(entry, &entry_path, files);
+            }
+        }
+    }
+
+    /// Serialize every block of the image, in order
+    pub fn write_to<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        self.flush_bitmap();
+        self.finish_fs();
+        for block in &self.blocks {
+            out.write_all(&block.b_data)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the image into memory, to inspect it before saving
+    pub fn to_image(&mut self) -> Image {
+        let mut data = Vec::with_capacity(self.blocks.len() * BLOCK_SIZE as usize);
+        self.write_to(&mut data)
+            .expect("writing to memory cannot fail");
+        Image::from_bytes(data).expect("the root is always a valid directory")
+    }
+
+    /// Write the image to a host file
+    pub fn save(&mut self, path: &str) -> Result<(), DiskError> {
+        let mut file = std::fs::File::create(path).map_err(|e| DiskError::io(path, e))?;
+        self.write_to(&mut file).map_err(|e| DiskError::io(path, e))
+    }
+
+    fn file(&self, file: FileRef) -> &File {
+        match file {
+            FileRef::Root => &self.super_block.s_root,
+            FileRef::Slot(block, n) => self.blocks[block as usize].as_file(n),
+        }
+    }
+
+    fn file_mut(&mut self, file: FileRef) -> &mut File {
+        match file {
+            FileRef::Root => &mut self.super_block.s_root,
+            FileRef::Slot(block, n) => self.blocks[block as usize].as_file_mut(n),
+        }
+    }
+
+    /// Find the directory at an absolute path such as `/bin`
+    fn lookup_dir(&self, path: &str) -> Result<FileRef, DiskError> {
+        let mut dir = FileRef::Root;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            dir = self
+                .find_file(dir, name)
+                .filter(|&file| self.file(file).get_type() == FileType::Directory)
+                .ok_or_else(|| DiskError::NoSuchDirectory {
+                    path: path.to_string(),
+                })?;
+        }
+        Ok(dir)
+    }
+
+    /// Find the entry at `path` along with the directory that holds it
+    fn lookup_entry(&self, path: &str) -> Result<(FileRef, FileRef), DiskError> {
+        let names = split_names(path)?;
+        let (&name, parent) = names.split_last().ok_or_else(|| DiskError::BadPath {
+            path: path.to_string(),
+            reason: "the root directory cannot be moved or removed",
+        })?;
+        let dir = self.lookup_dir(&format!("/{}", parent.join("/")))?;
+        let file = self
+            .find_file(dir, name)
+            .ok_or_else(|| DiskError::NotFound {
+                path: path.to_string(),
+            })?;
+        Ok((dir, file))
+    }
+
+    /// In a dry run, record an entry that cannot be stored and carry on with the rest
+    pub(crate) fn recover(&mut self, result: Result<(), DiskError>) -> Result<(), DiskError> {
+        match result {
+            Err(
+                e @ (DiskError::NameTooLong { .. }
+                | DiskError::FileTooLarge { .. }
+                | DiskError::NonUtf8Name { .. }
+                | DiskError::UnsupportedType { .. }
+                | DiskError::BadSymlink { .. }),
+            ) if self.dry_run => {
+                self.violations.push(e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn out_of_blocks(&self, path: &str) -> DiskError {
+        DiskError::OutOfBlocks {
+            path: path.to_string(),
+            block_cnt: self.block_cnt(),
+        }
+    }
+
+    /// Copy the host entry at `path`; `rel` is its path below the directory given to `add_path`
+    fn write_path(
+        &mut self,
+        dir: FileRef,
+        name: &str,
+        path: &str,
+        rel: &str,
+    ) -> Result<(), DiskError> {
+        let mut metadata = std::fs::symlink_metadata(path).map_err(|e| DiskError::io(path, e))?;
+        let target = if metadata.is_symlink() && self.preserve_symlinks {
+            self.write_symlink(dir, name, path)?
+        } else {
+            if metadata.is_symlink() {
+                eprintln!("Warning: following symlink '{}'", path);
+                metadata = std::fs::metadata(path).map_err(|e| DiskError::io(path, e))?;
+            }
+            if metadata.is_dir() {
+                self.write_dir(dir, name, path, rel)?
+            } else if metadata.is_file() {
+                self.write_file(dir, name, path)?
             } else {
-                self.write_file(target, &entry_path);
+                return Err(DiskError::UnsupportedType {
+                    path: path.to_string(),
+                });
             }
+        };
+        self.stamp(target, Some(host_attributes(&metadata)));
+        Ok(())
+    }
+
+    fn write_dir(
+        &mut self,
+        dir: FileRef,
+        name: &str,
+        path: &str,
+        rel: &str,
+    ) -> Result<FileRef, DiskError> {
+        let host_dir = std::fs::read_dir(path).map_err(|e| DiskError::io(path, e))?;
+        let target = self.make_dir(dir, name, path)?;
+        let mut entry_names = Vec::new();
+        for entry in host_dir {
+            let entry = entry.map_err(|e| DiskError::io(path, e))?;
+            let entry_name =
+                entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|_| DiskError::NonUtf8Name {
+                        path: entry.path().display().to_string(),
+                    })?;
+            entry_names.push(entry_name);
+        }
+        if !self.host_order {
+            entry_names.sort();
+        }
+
+        let ignore_path = format!("{}/{}", path, IGNORE_FILE);
+        let has_ignore_file = self.filter.fsignore && entry_names.iter().any(|n| n == IGNORE_FILE);
+        if has_ignore_file {
+            let text = std::fs::read_to_string(&ignore_path)
+                .map_err(|e| DiskError::io(&ignore_path, e))?;
+            self.ignores.push(IgnoreFile {
+                dir: rel.to_string(),
+                globs: parse_ignore_file(&text),
+            });
+        }
+        let result = self.write_entries(target, path, rel, &entry_names);
+        if has_ignore_file {
+            self.ignores.pop();
         }
+        result.map(|_| target)
     }
 
-    pub fn write_file(&mut self, dir: &mut File, path: &str) {
-        let target = create_file(dir);
-        let file_name = path.split('/').last().unwrap();
-        let file = std::fs::read(path).unwrap();
-        if file_name.len() >= MAX_NAME_LEN as usize {
-            panic!("File name too long");
+    fn write_entries(
+        &mut self,
+        dir: FileRef,
+        path: &str,
+        rel: &str,
+        entry_names: &[String],
+    ) -> Result<(), DiskError> {
+        for entry_name in entry_names {
+            let entry_path = format!("{}/{}", path, entry_name);
+            let entry_rel = match rel {
+                "" => entry_name.clone(),
+                _ => format!("{}/{}", rel, entry_name),
+            };
+            let is_dir = match std::fs::symlink_metadata(&entry_path) {
+                Ok(m) if m.is_symlink() && self.preserve_symlinks => false,
+                _ => Path::new(&entry_path).is_dir(),
+            };
+            if let Some(reason) = self.filter.skip_reason(&entry_rel, is_dir, &self.ignores) {
+                if self.filter.verbose {
+                    eprintln!("Skipping '{}': {}", entry_path, reason);
+                }
+                continue;
+            }
+            let result = check_name(entry_name, &entry_path)
+                .and_then(|_| self.write_path(dir, entry_name, &entry_path, &entry_rel));
+            self.recover(result)?;
+        }
+        Ok(())
+    }
+
+    fn write_file(&mut self, dir: FileRef, name: &str, path: &str) -> Result<FileRef, DiskError> {
+        let file = std::fs::read(path).map_err(|e| DiskError::io(path, e))?;
+        self.write_data(dir, name, &file, FileType::File, path)
+    }
+
+    fn write_symlink(
+        &mut self,
+        dir: FileRef,
+        name: &str,
+        path: &str,
+    ) -> Result<FileRef, DiskError> {
+        let target = std::fs::read_link(path).map_err(|e| DiskError::io(path, e))?;
+        let target = target.to_str().ok_or_else(|| DiskError::NonUtf8Name {
+            path: path.to_string(),
+        })?;
+        self.write_link(dir, name, target, path)
+    }
+
+    fn write_link(
+        &mut self,
+        dir: FileRef,
+        name: &str,
+        target: &str,
+        path: &str,
+    ) -> Result<FileRef, DiskError> {
+        if target.is_empty() || target.len() >= MAX_PATH_LEN as usize {
+            return Err(DiskError::BadSymlink {
+                path: path.to_string(),
+            });
         }
-        if file.len() >= MAX_FILE_SIZE as usize {
-            panic!("File too large");
+        self.super_block.set_feature(FEATURE_SYMLINK);
+        self.write_data(dir, name, target.as_bytes(), FileType::Symlink, path)
+    }
+
+    /// Get the directory `name` in `dir`, creating it if needed.
+    /// A file of that name is not replaced.
+    fn make_dir(&mut self, dir: FileRef, name: &str, path: &str) -> Result<FileRef, DiskError> {
+        let target = match self.find_file(dir, name) {
+            Some(old) if self.file(old).get_type() == FileType::Directory => return Ok(old),
+            Some(_) => {
+                return Err(DiskError::AlreadyExists {
+                    path: path.to_string(),
+                })
+            }
+            None => self
+                .create_file(dir)
+                .ok_or_else(|| self.out_of_blocks(path))?,
+        };
+        let target_file = self.file_mut(target);
+        target_file.set_name(name);
+        target_file.set_type(FileType::Directory);
+        self.stamp(target, None);
+        Ok(target)
+    }
+
+    fn write_data(
+        &mut self,
+        dir: FileRef,
+        name: &str,
+        data: &[u8],
+        file_type: FileType,
+        path: &str,
+    ) -> Result<FileRef, DiskError> {
+        if data.len() as u64 > self.max_file_size() {
+            return Err(DiskError::FileTooLarge {
+                path: path.to_string(),
+                size: data.len() as u64,
+                max: self.max_file_size(),
+            });
         }
-        target.set_name(file_name);
-        target.set_type(FileType::File);
-        target.set_size(file.len() as u32);
-        let block_cnt = (file.len() as u32 + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let target = match self.find_file(dir, name) {
+            // Only files and symlinks are replaced, never a directory tree
+            Some(old) if self.file(old).get_type() == FileType::Directory => {
+                return Err(DiskError::AlreadyExists {
+                    path: path.to_string(),
+                })
+            }
+            Some(old) => {
+                self.free_file(old);
+                old
+            }
+            None => self
+                .create_file(dir)
+                .ok_or_else(|| self.out_of_blocks(path))?,
+        };
+        let target_file = self.file_mut(target);
+        target_file.set_name(name);
+        target_file.set_type(file_type);
+        target_file.set_size(data.len() as u32);
+        self.stamp(target, None);
+        let hash = match (&self.dedup, file_type) {
+            (Some(_), FileType::File) if !data.is_empty() => {
+                let mut hasher = DefaultHasher::new();
+                data.hash(&mut hasher);
+                Some(hasher.finish())
+            }
+            _ => None,
+        };
+        if let Some(source) = hash.and_then(|hash| self.find_duplicate(hash, data, target)) {
+            self.share_blocks(source, target);
+            return Ok(target);
+        }
+        let block_cnt = (data.len() as u32).div_ceil(BLOCK_SIZE);
         for i in 0..block_cnt {
-            let block_number = next_block(BlockType::Data);
-            let block = &mut self.blocks[block_number as usize];
             let start = i as usize * BLOCK_SIZE as usize;
-            let end = std::cmp::min((i + 1) as usize * BLOCK_SIZE as usize, file.len());
-            block.b_data[..end - start].copy_from_slice(&file[start..end]);
-            save_block_link(target, i, block_number);
+            let end = std::cmp::min((i + 1) as usize * BLOCK_SIZE as usize, data.len());
+            if self.sparse
+                && file_type == FileType::File
+                && data[start..end].iter().all(|&b| b == 0)
+            {
+                self.super_block.set_feature(FEATURE_SPARSE);
+                self.sparse_saved += BLOCK_SIZE as u64;
+                continue;
+            }
+            let block_number = self
+                .next_block(BlockType::Data)
+                .ok_or_else(|| self.out_of_blocks(path))?;
+            let block = &mut self.blocks[block_number as usize];
+            block.b_data[..end - start].copy_from_slice(&data[start..end]);
+            self.save_block_link(target, i, block_number)
+                .ok_or_else(|| self.out_of_blocks(path))?;
+        }
+        if let (Some(hash), Some(dedup)) = (hash, &mut self.dedup) {
+            dedup.entry(hash).or_default().push(target);
         }
+        Ok(target)
     }
-}
-fn next_block(block_type: BlockType) -> u32 {
-    disk_mut().blocks[disk_mut().next_block as usize].set_type(block_type);
-    disk_mut().next_block += 1;
-    disk_mut().next_block - 1
-}
 
-fn save_block_link(dir: &mut File, block_cnt: u32, block_number: u32) {
-    assert!(block_cnt < INDIRECT_PTR_CNT);
+    /// An earlier regular file holding exactly `data`, other than `target`.
+    /// Records seen before may have been replaced since, so each candidate is read back.
+    fn find_duplicate(&self, hash: u64, data: &[u8], target: FileRef) -> Option<FileRef> {
+        let candidates = self.dedup.as_ref()?.get(&hash)?;
+        candidates.iter().copied().find(|&candidate| {
+            let still_file = match candidate {
+                FileRef::Slot(block_number, _) => {
+                    self.blocks[block_number as usize].get_type() == BlockType::File
+                }
+                FileRef::Root => false,
+            };
+            still_file
+                && candidate != target
+                && self.file(candidate).get_type() == FileType::File
+                && self.file(candidate).get_size() as usize == data.len()
+                && self.read_data(candidate) == data
+        })
+    }
 
-    if block_cnt < DIRECT_PTR_CNT {
-        dir.set_direct(block_cnt, block_number);
-    } else {
-        if dir.get_indirect() == 0 {
-            let new_block = next_block(BlockType::Index);
-            dir.set_indirect(new_block);
+    /// Contents of a regular file, with holes as zeros
+    fn read_data(&self, file: FileRef) -> Vec<u8> {
+        let size = self.file(file).get_size() as usize;
+        let mut data = Vec::with_capacity(size);
+        for i in 0..(size as u32).div_ceil(BLOCK_SIZE) {
+            match self.file_block(file, i) {
+                0 => data.extend_from_slice(&[0; BLOCK_SIZE as usize]),
+                n => data.extend_from_slice(&self.blocks[n as usize].b_data),
+            }
         }
-        disk_mut().blocks[dir.get_indirect() as usize].write_u32(block_cnt, block_number);
+        data.truncate(size);
+        data
     }
-}
 
-fn make_link_block(dir: &mut File, block_cnt: u32) -> u32 {
-    let block_number = next_block(BlockType::File);
-    save_block_link(dir, block_cnt, block_number);
-    dir.set_size(dir.get_size() + BLOCK_SIZE);
-    block_number
-}
+    /// Point `target` at the data and index blocks of `source`
+    fn share_blocks(&mut self, source: FileRef, target: FileRef) {
+        let block_cnt = self.file(source).get_size().div_ceil(BLOCK_SIZE);
+        let mut blocks: Vec<u32> = (0..block_cnt)
+            .map(|i| self.file_block(source, i))
+            .filter(|&n| n != 0)
+            .collect();
+        blocks.extend(self.index_blocks(source));
+        for &block_number in &blocks {
+            *self.shared.entry(block_number).or_insert(1) += 1;
+        }
+        self.super_block.set_feature(FEATURE_SHARED_DATA);
+        let source = self.file(source).clone();
+        let target = self.file_mut(target);
+        target.set_size(source.get_size());
+        for i in 0..DIRECT_PTR_CNT {
+            target.set_direct(i, source.get_direct(i));
+        }
+        target.set_indirect(source.get_indirect());
+        target.set_dindirect(source.get_dindirect());
+    }
 
-fn create_file(dir: &mut File) -> &'static mut File {
-    let block_cnt = dir.get_size() / BLOCK_SIZE;
-    for i in 0..block_cnt {
-        let block_number = match i {
-            i if i < DIRECT_PTR_CNT => dir.get_direct(i),
-            i => disk().blocks[dir.get_indirect() as usize].as_block_index(i),
+    /// Fill in the metadata of `file` from `source`, or from defaults, applying any overrides
+    fn stamp(&mut self, file: FileRef, source: Option<Attributes>) {
+        let Some(overrides) = &self.metadata else {
+            return;
         };
-        let block = &disk().blocks[block_number as usize];
-        for j in 0..BLOCK_SIZE / size_of::<&mut File>() as u32 {
-            let file = block.as_file_index(j);
-            if file.get_name() == "" {
-                return file;
+        let file_type = self.file(file).get_type();
+        let source = source.unwrap_or_else(|| Attributes::default_for(file_type));
+        let mode = match file_type {
+            FileType::File => overrides.file_mode,
+            FileType::Directory => overrides.dir_mode,
+            FileType::Symlink => None,
+        }
+        .unwrap_or(source.mode);
+        let uid = overrides.uid.unwrap_or(source.uid);
+        let gid = overrides.gid.unwrap_or(source.gid);
+        let mtime = overrides.mtime.unwrap_or(source.mtime);
+        let file = self.file_mut(file);
+        file.set_mode(mode);
+        file.set_owner(uid, gid);
+        file.set_mtime(mtime);
+    }
+
+    /// Allocate the first free block and clear it, or `None` if the disk is full
+    fn next_block(&mut self, block_type: BlockType) -> Option<u32> {
+        let free = (self.next_free..self.block_cnt())
+            .find(|&i| self.blocks[i as usize].get_type() == BlockType::Free);
+        let block_number = match free {
+            Some(block_number) => block_number,
+            None if self.dry_run => {
+                self.blocks.push(Block::new());
+                self.block_cnt() - 1
+            }
+            None => return None,
+        };
+        self.next_free = block_number + 1;
+        let block = &mut self.blocks[block_number as usize];
+        block.set_type(block_type);
+        block.b_data.fill(0);
+        Some(block_number)
+    }
+
+    fn free_block(&mut self, block_number: u32) {
+        self.next_free = self.next_free.min(block_number);
+        let block = &mut self.blocks[block_number as usize];
+        block.set_type(BlockType::Free);
+        block.b_data.fill(0);
+    }
+
+    /// Release every block owned by `file`, recursing into directories, and clear its record
+    fn free_file(&mut self, file: FileRef) {
+        let block_cnt = self.file(file).get_size().div_ceil(BLOCK_SIZE);
+        let is_dir = self.file(file).get_type() == FileType::Directory;
+        for i in 0..block_cnt {
+            let block_number = self.file_block(file, i);
+            if block_number == 0 {
+                continue;
+            }
+            if is_dir {
+                for j in 0..FILE2BLK {
+                    let entry = FileRef::Slot(block_number, j);
+                    if !self.file(entry).get_name().is_empty() {
+                        self.free_file(entry);
+                    }
+                }
             }
+            self.release_block(block_number);
         }
+        for block_number in self.index_blocks(file) {
+            self.release_block(block_number);
+        }
+        *self.file_mut(file) = File::new();
     }
-    return disk_mut().blocks[make_link_block(dir, block_cnt) as usize].as_file_index(0);
+
+    /// Drop one record's use of a block, freeing it once no record uses it
+    fn release_block(&mut self, block_number: u32) {
+        match self.shared.get_mut(&block_number) {
+            Some(uses) if *uses > 2 => *uses -= 1,
+            Some(_) => {
+                self.shared.remove(&block_number);
+            }
+            None => self.free_block(block_number),
+        }
+    }
+
+    fn save_block_link(&mut self, file: FileRef, block_cnt: u32, block_number: u32) -> Option<()> {
+        assert!(block_cnt < self.max_block_cnt());
+
+        if block_cnt < DIRECT_PTR_CNT {
+            self.file_mut(file).set_direct(block_cnt, block_number);
+        } else if block_cnt < INDIRECT_PTR_CNT {
+            if self.file(file).get_indirect() == 0 {
+                let new_block = self.next_block(BlockType::Index)?;
+                self.file_mut(file).set_indirect(new_block);
+            }
+            let indirect = self.file(file).get_indirect();
+            self.blocks[indirect as usize].write_u32(block_cnt, block_number);
+        } else {
+            let n = block_cnt - INDIRECT_PTR_CNT;
+            if self.file(file).get_dindirect() == 0 {
+                let new_block = self.next_block(BlockType::Index)?;
+                self.file_mut(file).set_dindirect(new_block);
+            }
+            let dindirect = self.file(file).get_dindirect();
+            let mut index = self.blocks[dindirect as usize].as_block_index(n / INDIRECT_PTR_CNT);
+            if index == 0 {
+                index = self.next_block(BlockType::Index)?;
+                self.blocks[dindirect as usize].write_u32(n / INDIRECT_PTR_CNT, index);
+            }
+            self.blocks[index as usize].write_u32(n % INDIRECT_PTR_CNT, block_number);
+        }
+        Some(())
+    }
+
+    /// Number of blocks a single file can address in this image's layout
+    fn max_block_cnt(&self) -> u32 {
+        match self.super_block.has_feature(FEATURE_DOUBLE_INDIRECT) {
+            true => INDIRECT_PTR_CNT + DINDIRECT_PTR_CNT,
+            false => INDIRECT_PTR_CNT,
+        }
+    }
+
+    /// Largest file size this image's layout can hold
+    fn max_file_size(&self) -> u64 {
+        match self.super_block.has_feature(FEATURE_DOUBLE_INDIRECT) {
+            true => MAX_DINDIRECT_FILE_SIZE,
+            false => MAX_FILE_SIZE as u64 - 1,
+        }
+    }
+
+    fn make_link_block(&mut self, dir: FileRef, block_cnt: u32) -> Option<u32> {
+        let block_number = self.next_block(BlockType::File)?;
+        self.save_block_link(dir, block_cnt, block_number)?;
+        let dir = self.file_mut(dir);
+        dir.set_size(dir.get_size() + BLOCK_SIZE);
+        Some(block_number)
+    }
+
+    /// Block number `i` of `file`, or 0 for a hole
+    fn file_block(&self, file: FileRef, i: u32) -> u32 {
+        let file = self.file(file);
+        let index = |block: u32, n: u32| match block {
+            0 => 0,
+            _ => self.blocks[block as usize].as_block_index(n),
+        };
+        match i {
+            i if i < DIRECT_PTR_CNT => file.get_direct(i),
+            i if i < INDIRECT_PTR_CNT => index(file.get_indirect(), i),
+            i => {
+                let i = i - INDIRECT_PTR_CNT;
+                let second = index(file.get_dindirect(), i / INDIRECT_PTR_CNT);
+                index(second, i % INDIRECT_PTR_CNT)
+            }
+        }
+    }
+
+    /// Indirect, double-indirect and second-level index blocks owned by `file`
+    fn index_blocks(&self, file: FileRef) -> Vec<u32> {
+        let file = self.file(file);
+        let mut blocks = Vec::new();
+        if file.get_indirect() != 0 {
+            blocks.push(file.get_indirect());
+        }
+        if file.get_dindirect() != 0 {
+            blocks.push(file.get_dindirect());
+            let dindirect = &self.blocks[file.get_dindirect() as usize];
+            for i in 0..INDIRECT_PTR_CNT {
+                if dindirect.as_block_index(i) != 0 {
+                    blocks.push(dindirect.as_block_index(i));
+                }
+            }
+        }
+        blocks
+    }
+
+    fn create_file(&mut self, dir: FileRef) -> Option<FileRef> {
+        let block_cnt = self.file(dir).get_size() / BLOCK_SIZE;
+        for i in 0..block_cnt {
+            let block_number = self.file_block(dir, i);
+            for j in 0..FILE2BLK {
+                if self.blocks[block_number as usize]
+                    .as_file(j)
+                    .get_name()
+                    .is_empty()
+                {
+                    return Some(FileRef::Slot(block_number, j));
+                }
+            }
+        }
+        Some(FileRef::Slot(self.make_link_block(dir, block_cnt)?, 0))
+    }
+
+    /// Occupied slots of a directory
+    fn dir_entries(&self, dir: FileRef) -> Vec<FileRef> {
+        let mut entries = Vec::new();
+        for i in 0..self.file(dir).get_size() / BLOCK_SIZE {
+            let block_number = self.file_block(dir, i);
+            for j in 0..FILE2BLK {
+                let entry = FileRef::Slot(block_number, j);
+                if !self.file(entry).get_name().is_empty() {
+                    entries.push(entry);
+                }
+            }
+        }
+        entries
+    }
+
+    /// Free trailing directory blocks left without entries, so `f_size` stays
+    /// what `make_link_block` would have grown the directory to
+    fn trim_dir(&mut self, dir: FileRef) {
+        while self.file(dir).get_size() > 0 {
+            let last = self.file(dir).get_size() / BLOCK_SIZE - 1;
+            let block_number = self.file_block(dir, last);
+            if (0..FILE2BLK).any(|j| {
+                !self
+                    .file(FileRef::Slot(block_number, j))
+                    .get_name()
+                    .is_empty()
+            }) {
+                break;
+            }
+            self.free_block(block_number);
+            self.clear_block_link(dir, last);
+            let dir = self.file_mut(dir);
+            dir.set_size(dir.get_size() - BLOCK_SIZE);
+        }
+    }
+
+    /// Clear the pointer to `file`'s last block `i`, freeing index blocks left empty
+    fn clear_block_link(&mut self, file: FileRef, i: u32) {
+        if i < DIRECT_PTR_CNT {
+            self.file_mut(file).set_direct(i, 0);
+        } else if i < INDIRECT_PTR_CNT {
+            let indirect = self.file(file).get_indirect();
+            self.blocks[indirect as usize].write_u32(i, 0);
+            if i == DIRECT_PTR_CNT {
+                self.free_block(indirect);
+                self.file_mut(file).set_indirect(0);
+            }
+        } else {
+            let n = i - INDIRECT_PTR_CNT;
+            let dindirect = self.file(file).get_dindirect();
+            let index = self.blocks[dindirect as usize].as_block_index(n / INDIRECT_PTR_CNT);
+            self.blocks[index as usize].write_u32(n % INDIRECT_PTR_CNT, 0);
+            if n.is_multiple_of(INDIRECT_PTR_CNT) {
+                self.free_block(index);
+                self.blocks[dindirect as usize].write_u32(n / INDIRECT_PTR_CNT, 0);
+            }
+            if n == 0 {
+                self.free_block(dindirect);
+                self.file_mut(file).set_dindirect(0);
+            }
+        }
+    }
+
+    fn find_file(&self, dir: FileRef, name: &str) -> Option<FileRef> {
+        for i in 0..self.file(dir).get_size() / BLOCK_SIZE {
+            let block_number = self.file_block(dir, i);
+            for j in 0..FILE2BLK {
+                if self.blocks[block_number as usize].as_file(j).get_name() == name {
+                    return Some(FileRef::Slot(block_number, j));
+                }
+            }
+        }
+        None
+    }
+
+    /// Rewrite the bitmap from the block types: free blocks get a set bit
+    fn flush_bitmap(&mut self) {
+        for i in 0..self.block_cnt() {
+            let block_number = i / BLOCK_SIZE_BIT;
+            let bit_number = i % BLOCK_SIZE_BIT;
+            let free = self.blocks[i as usize].get_type() == BlockType::Free;
+            let bit_block_number = self.super_block.bitmap_start() + block_number;
+            let bit_block = &mut self.blocks[bit_block_number as usize];
+            let offset = (bit_number / 32) as usize * 4;
+            let mut value =
+                u32::from_le_bytes(bit_block.b_data[offset..offset + 4].try_into().unwrap());
+            match free {
+                true => value |= 1 << (bit_number % 32),
+                false => value &= !(1 << (bit_number % 32)),
+            }
+            bit_block.b_data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Copy the superblock into its block, then fill in the checksum table if there is one
+    fn finish_fs(&mut self) {
+        let bytes = self.super_block.to_bytes();
+        let block_number = self.super_block.get_boot_cnt() as usize;
+        self.blocks[block_number].b_data[..bytes.len()].copy_from_slice(bytes);
+
+        let Some(table) = self.super_block.checksum_blocks() else {
+            return;
+        };
+        let crcs: Vec<u32> = self
+            .blocks
+            .iter()
+            .map(|block| match block.get_type() {
+                BlockType::Free | BlockType::Checksum => 0,
+                _ => crc32(&block.b_data),
+            })
+            .collect();
+        for (i, chunk) in crcs.chunks(CRC_PER_BLOCK as usize).enumerate() {
+            let block = &mut self.blocks[(table.start + i as u32) as usize];
+            block.b_data.fill(0);
+            for (j, crc) in chunk.iter().enumerate() {
+                block.b_data[j * 4..j * 4 + 4].copy_from_slice(&crc.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Name an entry gets in the image: the last component of its host path
+fn entry_name(path: &str) -> Result<&str, DiskError> {
+    let name = path.trim_end_matches('/').split('/').next_back().unwrap();
+    check_name(name, path)?;
+    Ok(name)
+}
+
+/// Components of an image path, rejecting `.` and `..`
+fn split_names(path: &str) -> Result<Vec<&str>, DiskError> {
+    let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    if names.iter().any(|&name| name == "." || name == "..") {
+        return Err(DiskError::BadPath {
+            path: path.to_string(),
+            reason: "'.' and '..' are not supported in image paths",
+        });
+    }
+    Ok(names)
+}
+
+fn check_name(name: &str, path: &str) -> Result<(), DiskError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(DiskError::BadPath {
+            path: path.to_string(),
+            reason: "not a valid file name",
+        });
+    }
+    if name.len() >= MAX_NAME_LEN as usize {
+        return Err(DiskError::NameTooLong {
+            path: path.to_string(),
+        });
+    }
+    Ok(())
 }
 
-pub fn flush_bitmap() {
-    for i in 0..disk().next_block {
-        let block_number = i / BLOCK_SIZE_BIT;
-        let bit_number = i % BLOCK_SIZE_BIT;
-        let bit_block = &mut disk_mut().blocks[2 + block_number as usize];
-        let value = !(1 << (bit_number % 32));
-        let bytes: &[u8; 4] = unsafe { std::mem::transmute(&value) };
-        (0..4).for_each(|j| bit_block.b_data[(bit_number / 32) as usize * 4 + j] &= bytes[j]);
+#[cfg(unix)]
+fn host_attributes(metadata: &std::fs::Metadata) -> Attributes {
+    use std::os::unix::fs::MetadataExt;
+
+    Attributes {
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime().clamp(0, u32::MAX as i64) as u32,
     }
 }
 
-pub fn finish_fs(name: &str) {
-    disk()
-        .super_block
-        .to_bytes()
-        .iter()
-        .enumerate()
-        .for_each(|(i, &b)| disk_mut().blocks[1].b_data[i] = b);
-    let mut file = std::fs::File::create(name).unwrap();
-    for i in 0..1024 {
-        file.write_all(&disk().blocks[i].b_data).unwrap();
+#[cfg(not(unix))]
+fn host_attributes(metadata: &std::fs::Metadata) -> Attributes {
+    let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs().min(u32::MAX as u64) as u32);
+    Attributes {
+        mode,
+        uid: 0,
+        gid: 0,
+        mtime,
+    }
+}
+
+/// Join a directory path and an entry name
+pub(crate) fn join_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// Split an image path into its parent directory and name, with no name for the root
+pub(crate) fn split_path(path: &str) -> (&str, Option<&str>) {
+    match path.rsplit_once('/') {
+        Some(("", "")) => ("/", None),
+        Some(("", name)) => ("/", Some(name)),
+        Some((dir, name)) => (dir, Some(name)),
+        None => ("/", Some(path)),
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+use crate::fs::{File, FileType};
+use crate::image::Image;
+
+/// Rebuild the directory tree of an image under `out_dir` on the host.
+/// Modes and mtimes are restored when the image records them; owners are not.
+pub fn extract(image: &Image, out_dir: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(out_dir)?;
+    extract_dir(
+        image,
+        &image.super_block.s_root,
+        Path::new(out_dir),
+        &mut HashSet::new(),
+    )
+}
+
+fn extract_dir(
+    image: &Image,
+    dir: &File,
+    host_dir: &Path,
+    visited: &mut HashSet<u32>,
+) -> std::io::Result<()> {
+    if !image.visit_dir(dir, visited) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "'{}' reuses a directory block, the image is corrupt",
+                host_dir.display()
+            ),
+        ));
+    }
+    for entry in image.read_dir(dir) {
+        let name = entry.get_name();
+        if name == "." || name == ".." || name.contains('/') {
+            eprintln!("Warning: skipping entry with unsafe name '{}'", name);
+            continue;
+        }
+        let host_path = host_dir.join(&name);
+        let existing = match std::fs::symlink_metadata(&host_path) {
+            Ok(metadata) => Some(metadata.file_type()),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        match entry.get_type() {
+            FileType::Directory => {
+                // A corrupt image may list a name twice; never descend through a symlink
+                if existing.is_some_and(|t| t.is_symlink()) {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("'{}' is a symlink", host_path.display()),
+                    ));
+                }
+                if existing.is_none() {
+                    std::fs::create_dir(&host_path)?;
+                }
+                extract_dir(image, &entry, &host_path, visited)?;
+            }
+            // Replace a stale file or link; neither `remove_file` nor `create_new` follows one
+            FileType::File => {
+                if existing.is_some_and(|t| !t.is_dir()) {
+                    std::fs::remove_file(&host_path)?;
+                }
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&host_path)?
+                    .write_all(&image.read_file(&entry))?;
+            }
+            FileType::Symlink => {
+                if existing.is_some_and(|t| !t.is_dir()) {
+                    std::fs::remove_file(&host_path)?;
+                }
+                extract_symlink(image, &entry, &host_path)?;
+                continue;
+            }
+        }
+        if image.super_block.has_metadata() {
+            restore_metadata(&entry, &host_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn restore_metadata(entry: &File, host_path: &Path) -> std::io::Result<()> {
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.get_mtime().into());
+    std::fs::File::open(host_path)?.set_modified(mtime)?;
+    set_mode(host_path, entry.get_mode())
+}
+
+#[cfg(unix)]
+fn set_mode(host_path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(host_path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(host_path: &Path, mode: u32) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(host_path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(host_path, permissions)
+}
+
+#[cfg(unix)]
+fn extract_symlink(image: &Image, entry: &File, host_path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let target = image.read_file(entry);
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(&target), host_path)
+}
+
+/// Hosts without symlinks get a plain file holding the target path
+#[cfg(not(unix))]
+fn extract_symlink(image: &Image, entry: &File, host_path: &Path) -> std::io::Result<()> {
+    eprintln!(
+        "Warning: writing symlink '{}' as a regular file",
+        host_path.display()
+    );
+    std::fs::write(host_path, image.read_file(entry))
+}
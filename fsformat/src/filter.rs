@@ -0,0 +1,166 @@
+//! Glob filters for the host directories copied into an image.
+//!
+//! Patterns use `*` and `?` (which do not match `/`), `[...]` classes with ranges
+//! and `!` negation, and `**` for any number of directories. A pattern without a
+//! `/` is matched against the entry name; one with a `/` is matched against the
+//! path relative to the directory being copied, or to the `.fsignore` holding it.
+//! A trailing `/` restricts the pattern to directories.
+
+/// A compiled glob pattern
+pub struct Glob {
+    pattern: String,
+    tokens: Vec<char>,
+    anchored: bool,
+    dir_only: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let dir_only = pattern.ends_with('/');
+        let trimmed = pattern.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        Glob {
+            pattern: pattern.to_string(),
+            tokens: trimmed.trim_start_matches('/').chars().collect(),
+            anchored,
+            dir_only,
+        }
+    }
+
+    /// Whether the entry at `rel_path` (relative, `/`-separated) matches
+    pub fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = match self.anchored {
+            true => rel_path,
+            false => rel_path.rsplit('/').next().unwrap(),
+        };
+        match_here(&self.tokens, &text.chars().collect::<Vec<_>>())
+    }
+}
+
+/// Which host entries to copy.
+/// Excludes win over includes; with any include given, regular files must match one.
+#[derive(Default)]
+pub struct Filter {
+    includes: Vec<Glob>,
+    excludes: Vec<Glob>,
+    /// Honour `.fsignore` files, whose patterns exclude entries in their directory and below
+    pub fsignore: bool,
+    /// Log every skipped path and the reason to stderr
+    pub verbose: bool,
+}
+
+/// Patterns from one `.fsignore`, with the directory it applies to
+pub(crate) struct IgnoreFile {
+    pub dir: String,
+    pub globs: Vec<Glob>,
+}
+
+pub(crate) const IGNORE_FILE: &str = ".fsignore";
+
+impl Filter {
+    pub fn include(&mut self, pattern: &str) {
+        self.includes.push(Glob::new(pattern));
+    }
+
+    pub fn exclude(&mut self, pattern: &str) {
+        self.excludes.push(Glob::new(pattern));
+    }
+
+    /// Why the entry at `rel_path` should be skipped, or `None` to copy it
+    pub(crate) fn skip_reason(
+        &self,
+        rel_path: &str,
+        is_dir: bool,
+        ignores: &[IgnoreFile],
+    ) -> Option<String> {
+        if let Some(glob) = self.excludes.iter().find(|g| g.matches(rel_path, is_dir)) {
+            return Some(format!("matches --exclude '{}'", glob.pattern));
+        }
+        for ignore in ignores {
+            let local = match ignore.dir.as_str() {
+                "" => rel_path,
+                dir => &rel_path[dir.len() + 1..],
+            };
+            if let Some(glob) = ignore.globs.iter().find(|g| g.matches(local, is_dir)) {
+                return Some(format!(
+                    "matches '{}' in {}",
+                    glob.pattern,
+                    match ignore.dir.as_str() {
+                        "" => IGNORE_FILE.to_string(),
+                        dir => format!("{}/{}", dir, IGNORE_FILE),
+                    }
+                ));
+            }
+        }
+        if self.fsignore && !is_dir && rel_path.rsplit('/').next() == Some(IGNORE_FILE) {
+            return Some("is an ignore file".to_string());
+        }
+        if !is_dir
+            && !self.includes.is_empty()
+            && !self.includes.iter().any(|g| g.matches(rel_path, is_dir))
+        {
+            return Some("matches no --include".to_string());
+        }
+        None
+    }
+}
+
+/// Parse a `.fsignore`: one pattern per line, blank lines and `#` comments ignored
+pub(crate) fn parse_ignore_file(text: &str) -> Vec<Glob> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Glob::new)
+        .collect()
+}
+
+fn match_here(p: &[char], t: &[char]) -> bool {
+    match p {
+        [] => t.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // "**/" may also match no directory at all
+            let after_slash = rest.strip_prefix(&['/']);
+            after_slash.is_some_and(|rest| match_here(rest, t))
+                || (0..=t.len()).any(|i| match_here(rest, &t[i..]))
+        }
+        ['*', rest @ ..] => (0..=t.len())
+            .take_while(|&i| i == 0 || t[i - 1] != '/')
+            .any(|i| match_here(rest, &t[i..])),
+        ['?', rest @ ..] => matches!(t, [c, ..] if *c != '/') && match_here(rest, &t[1..]),
+        ['[', rest @ ..] => match class(rest) {
+            Some((matcher, len)) => match t {
+                [c, ..] if *c != '/' && matcher(*c) => match_here(&rest[len..], &t[1..]),
+                _ => false,
+            },
+            None => matches!(t, ['[', ..]) && match_here(rest, &t[1..]),
+        },
+        ['\\', c, rest @ ..] => matches!(t, [x, ..] if x == c) && match_here(rest, &t[1..]),
+        [c, rest @ ..] => matches!(t, [x, ..] if x == c) && match_here(rest, &t[1..]),
+    }
+}
+
+/// Parse the body of a `[...]` class after the `[`.
+/// Returns a matcher and the number of pattern characters used, or `None` if unterminated.
+fn class(p: &[char]) -> Option<(impl Fn(char) -> bool, usize)> {
+    let negated = matches!(p, ['!' | '^', ..]);
+    let start = negated as usize;
+    // A ']' right at the start is a literal member
+    let end = start + 1 + p.get(start + 1..)?.iter().position(|&c| c == ']')?;
+    let body = &p[start..end];
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        if i + 2 < body.len() && body[i + 1] == '-' {
+            ranges.push((body[i], body[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((body[i], body[i]));
+            i += 1;
+        }
+    }
+    let matcher = move |c: char| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated;
+    Some((matcher, end + 1))
+}
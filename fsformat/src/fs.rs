@@ -1,3 +1,6 @@
+use std::mem::offset_of;
+use std::ops::Range;
+
 pub const BLOCK_SIZE: u32 = 0x1000;
 pub const BLOCK_SIZE_BIT: u32 = 0x8000;
 
@@ -8,17 +11,74 @@ pub const INDIRECT_PTR_CNT: u32 = BLOCK_SIZE / 4;
 
 pub const MAX_FILE_SIZE: u32 = (INDIRECT_PTR_CNT) * BLOCK_SIZE;
 
+/// Blocks reachable through `f_dindirect` when the image uses `FEATURE_DOUBLE_INDIRECT`
+pub const DINDIRECT_PTR_CNT: u32 = INDIRECT_PTR_CNT * INDIRECT_PTR_CNT;
+/// With double indirection the size is only bounded by the 32-bit `f_size`
+pub const MAX_DINDIRECT_FILE_SIZE: u64 = u32::MAX as u64;
+
+pub const MAX_PATH_LEN: u32 = 0x400;
+
 pub const FILE_STRUCT_SIZE: u32 = 0x100;
+pub const FILE2BLK: u32 = BLOCK_SIZE / FILE_STRUCT_SIZE;
 
 pub const FS_MAGIC: u32 = 0x68286097;
 
+/// Images written by the stock tools leave `s_version` and `s_features` zeroed
+pub const FS_VERSION_LEGACY: u32 = 0;
+/// `s_features` is valid
+pub const FS_VERSION_FEATURES: u32 = 1;
+/// Every `File` record also carries mode, owner and mtime
+pub const FS_VERSION_METADATA: u32 = 2;
+pub const FS_VERSION: u32 = FS_VERSION_METADATA;
+
+/// Permission bits kept in `f_mode`, as in `st_mode`
+pub const MODE_MASK: u32 = 0o7777;
+
+/// Files may address blocks past `INDIRECT_PTR_CNT` through `f_dindirect`
+pub const FEATURE_DOUBLE_INDIRECT: u32 = 1 << 0;
+/// The image may contain `FileType::Symlink` entries
+pub const FEATURE_SYMLINK: u32 = 1 << 1;
+/// Regular files may have null block pointers below `f_size`, which read as zeros
+pub const FEATURE_SPARSE: u32 = 1 << 2;
+/// Blocks after block 0 are reserved for a boot payload, and the superblock and bitmap
+/// follow them; `s_boot_cnt` gives the size of the whole boot area
+pub const FEATURE_BOOT_AREA: u32 = 1 << 3;
+/// `s_crc_cnt` blocks from `s_crc_start` hold a CRC-32 of every used block, one
+/// little-endian word per block number, with zero for free blocks and the table itself
+pub const FEATURE_CHECKSUMS: u32 = 1 << 4;
+/// Regular files with identical contents may point at the same data and index blocks
+pub const FEATURE_SHARED_DATA: u32 = 1 << 5;
+pub const FEATURES_KNOWN: u32 = FEATURE_DOUBLE_INDIRECT
+    | FEATURE_SYMLINK
+    | FEATURE_SPARSE
+    | FEATURE_BOOT_AREA
+    | FEATURE_CHECKSUMS
+    | FEATURE_SHARED_DATA;
+
+/// Checksums held by one block of the checksum table
+pub const CRC_PER_BLOCK: u32 = BLOCK_SIZE / 4;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(u32)]
 pub enum FileType {
     File = 0,
     Directory = 1,
+    /// The data blocks hold the target path, `f_size` bytes long
+    Symlink = 2,
+}
+
+impl FileType {
+    pub fn from_u32(value: u32) -> Option<FileType> {
+        match value {
+            0 => Some(FileType::File),
+            1 => Some(FileType::Directory),
+            2 => Some(FileType::Symlink),
+            _ => None,
+        }
+    }
 }
 
+#[derive(Clone)]
 #[repr(C, align(4))]
 pub struct File {
     f_name: [u8; MAX_NAME_LEN as usize],
@@ -26,14 +86,38 @@ pub struct File {
     f_type: FileType,
     f_direct: [u32; DIRECT_PTR_CNT as usize],
     f_indirect: u32,
-    _padding: [u8; 0x100 - MAX_NAME_LEN as usize - 4 - 4 - 4 * DIRECT_PTR_CNT as usize - 4],
+    // The kernel keeps its in-memory `f_dir` pointer here and writes it back to disk,
+    // so the word holds garbage in any image the kernel has touched
+    _f_dir: u32,
+    // Only meaningful from `FS_VERSION_METADATA` on; older images leave them zeroed
+    f_mode: u32,
+    f_uid: u32,
+    f_gid: u32,
+    f_mtime: u32,
+    // Only meaningful with `FEATURE_DOUBLE_INDIRECT`
+    f_dindirect: u32,
+    _padding: [u8; 0x100
+        - MAX_NAME_LEN as usize
+        - 4
+        - 4
+        - 4 * DIRECT_PTR_CNT as usize
+        - 4
+        - 4
+        - 4 * 4
+        - 4],
 }
 
+#[derive(Clone)]
 #[repr(C, align(4))]
 pub struct SuperBlock {
     s_magic: u32,
     s_block_cnt: u32,
     pub s_root: File,
+    s_version: u32,
+    s_features: u32,
+    s_boot_cnt: u32,
+    s_crc_start: u32,
+    s_crc_cnt: u32,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -46,6 +130,7 @@ pub enum BlockType {
     Data = 4,
     File = 5,
     Index = 6,
+    Checksum = 7,
 }
 
 #[derive(Copy, Clone)]
@@ -63,7 +148,21 @@ impl File {
             f_type: FileType::File,
             f_direct: [0; DIRECT_PTR_CNT as usize],
             f_indirect: 0,
-            _padding: [0; 0x100 - MAX_NAME_LEN as usize - 4 - 4 - 4 * DIRECT_PTR_CNT as usize - 4],
+            _f_dir: 0,
+            f_mode: 0,
+            f_uid: 0,
+            f_gid: 0,
+            f_mtime: 0,
+            f_dindirect: 0,
+            _padding: [0; 0x100
+                - MAX_NAME_LEN as usize
+                - 4
+                - 4
+                - 4 * DIRECT_PTR_CNT as usize
+                - 4
+                - 4
+                - 4 * 4
+                - 4],
         }
     }
 
@@ -96,10 +195,40 @@ impl File {
         self.f_indirect = block;
     }
 
+    /// Every block pointer of the record: direct, indirect, then double-indirect
+    pub fn pointers(&self) -> Vec<u32> {
+        let mut pointers = self.f_direct.to_vec();
+        pointers.push(self.f_indirect);
+        pointers.push(self.f_dindirect);
+        pointers
+    }
+
+    pub fn set_dindirect(&mut self, block: u32) {
+        self.f_dindirect = block;
+    }
+
+    pub fn set_mode(&mut self, mode: u32) {
+        self.f_mode = mode & MODE_MASK;
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.f_uid = uid;
+        self.f_gid = gid;
+    }
+
+    /// Modification time in seconds since the Unix epoch
+    pub fn set_mtime(&mut self, mtime: u32) {
+        self.f_mtime = mtime;
+    }
+
     pub fn get_size(&self) -> u32 {
         self.f_size
     }
 
+    pub fn get_type(&self) -> FileType {
+        self.f_type
+    }
+
     pub fn get_direct(&self, idx: u32) -> u32 {
         assert!(idx < DIRECT_PTR_CNT);
         self.f_direct[idx as usize]
@@ -109,15 +238,62 @@ impl File {
         self.f_indirect
     }
 
-    pub fn get_name(&self) -> String {
-        let mut name = String::new();
-        for i in 0..MAX_NAME_LEN as usize {
-            if self.f_name[i] == 0 {
-                break;
-            }
-            name.push(self.f_name[i] as char);
+    pub fn get_dindirect(&self) -> u32 {
+        self.f_dindirect
+    }
+
+    pub fn get_mode(&self) -> u32 {
+        self.f_mode
+    }
+
+    pub fn get_uid(&self) -> u32 {
+        self.f_uid
+    }
+
+    pub fn get_gid(&self) -> u32 {
+        self.f_gid
+    }
+
+    pub fn get_mtime(&self) -> u32 {
+        self.f_mtime
+    }
+
+    /// A copy of the record with its size and block pointers cleared
+    pub fn without_data(&self) -> File {
+        File {
+            f_size: 0,
+            f_direct: [0; DIRECT_PTR_CNT as usize],
+            f_indirect: 0,
+            f_dindirect: 0,
+            ..self.clone()
         }
-        name
+    }
+
+    pub fn get_name(&self) -> String {
+        let len = self
+            .f_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(MAX_NAME_LEN as usize);
+        String::from_utf8_lossy(&self.f_name[..len]).into_owned()
+    }
+
+    /// Parse a `File` record from raw disk bytes.
+    /// Returns `None` if the type field holds an unknown value.
+    pub fn from_bytes(data: &[u8]) -> Option<File> {
+        assert!(data.len() >= FILE_STRUCT_SIZE as usize);
+        let offset = offset_of!(File, f_type);
+        let t = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        FileType::from_u32(t)?;
+        let mut file = unsafe { (data.as_ptr() as *const File).read_unaligned() };
+        file._f_dir = 0;
+        Some(file)
+    }
+}
+
+impl Default for File {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -127,10 +303,107 @@ impl SuperBlock {
             s_magic: magic,
             s_block_cnt: block_count,
             s_root: File::new(),
+            s_version: FS_VERSION_LEGACY,
+            s_features: 0,
+            s_boot_cnt: 0,
+            s_crc_start: 0,
+            s_crc_cnt: 0,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<SuperBlock> {
+        let root = offset_of!(SuperBlock, s_root);
+        let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        Some(SuperBlock {
+            s_magic: word(offset_of!(SuperBlock, s_magic)),
+            s_block_cnt: word(offset_of!(SuperBlock, s_block_cnt)),
+            s_root: File::from_bytes(&data[root..])?,
+            s_version: word(offset_of!(SuperBlock, s_version)),
+            s_features: word(offset_of!(SuperBlock, s_features)),
+            s_boot_cnt: word(offset_of!(SuperBlock, s_boot_cnt)),
+            s_crc_start: word(offset_of!(SuperBlock, s_crc_start)),
+            s_crc_cnt: word(offset_of!(SuperBlock, s_crc_cnt)),
+        })
+    }
+
+    pub fn get_magic(&self) -> u32 {
+        self.s_magic
+    }
+
+    pub fn get_block_cnt(&self) -> u32 {
+        self.s_block_cnt
+    }
+
+    pub fn set_block_cnt(&mut self, block_count: u32) {
+        self.s_block_cnt = block_count;
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.s_version
+    }
+
+    /// Feature flags, which only exist from `FS_VERSION_FEATURES` on
+    pub fn get_features(&self) -> u32 {
+        match self.s_version {
+            FS_VERSION_LEGACY => 0,
+            _ => self.s_features,
+        }
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.get_features() & feature != 0
+    }
+
+    /// Turn on a feature, bumping the image to `FS_VERSION_FEATURES`
+    pub fn set_feature(&mut self, feature: u32) {
+        self.s_version = self.s_version.max(FS_VERSION_FEATURES);
+        self.s_features |= feature;
+    }
+
+    /// Blocks in the boot area, block 0 included
+    pub fn get_boot_cnt(&self) -> u32 {
+        match self.has_feature(FEATURE_BOOT_AREA) {
+            true => self.s_boot_cnt,
+            false => 1,
+        }
+    }
+
+    /// Reserve the first `boot_cnt` blocks for a boot payload.
+    /// Anything past block 0 needs `FEATURE_BOOT_AREA`.
+    pub fn set_boot_cnt(&mut self, boot_cnt: u32) {
+        if boot_cnt > 1 {
+            self.set_feature(FEATURE_BOOT_AREA);
+            self.s_boot_cnt = boot_cnt;
         }
     }
 
-    pub fn to_bytes(&self) -> &'static [u8] {
+    /// First bitmap block, right after the superblock, which follows the boot area
+    pub fn bitmap_start(&self) -> u32 {
+        self.get_boot_cnt() + 1
+    }
+
+    /// Blocks holding the checksum table, if the image has one
+    pub fn checksum_blocks(&self) -> Option<Range<u32>> {
+        self.has_feature(FEATURE_CHECKSUMS)
+            .then(|| self.s_crc_start..self.s_crc_start.saturating_add(self.s_crc_cnt))
+    }
+
+    pub fn set_checksum_blocks(&mut self, blocks: Range<u32>) {
+        self.set_feature(FEATURE_CHECKSUMS);
+        self.s_crc_start = blocks.start;
+        self.s_crc_cnt = blocks.len() as u32;
+    }
+
+    /// Whether `File` records carry mode, owner and mtime
+    pub fn has_metadata(&self) -> bool {
+        self.s_version >= FS_VERSION_METADATA
+    }
+
+    pub fn enable_metadata(&mut self) {
+        self.s_version = self.s_version.max(FS_VERSION_METADATA);
+    }
+
+    pub fn to_bytes(&self) -> &[u8] {
         unsafe {
             let ptr = self as *const SuperBlock as *const u8;
             let len = core::mem::size_of::<SuperBlock>();
@@ -140,6 +413,12 @@ impl SuperBlock {
     }
 }
 
+impl Default for Block {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Block {
     pub const fn new() -> Block {
         Block {
@@ -152,6 +431,10 @@ impl Block {
         self.b_type = t;
     }
 
+    pub fn get_type(&self) -> BlockType {
+        self.b_type
+    }
+
     pub fn as_block_index(&self, n: u32) -> u32 {
         assert!(self.b_type == BlockType::Index);
         unsafe {
@@ -160,14 +443,18 @@ impl Block {
         }
     }
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn as_file_index(&self, n: u32) -> &mut File {
-        assert!(self.b_type == BlockType::File);
-        unsafe { &mut *(self.b_data.as_ptr() as *mut File).add(n as usize) }
+    pub fn as_file(&self, n: u32) -> &File {
+        assert!(self.b_type == BlockType::File && n < FILE2BLK);
+        unsafe { &*(self.b_data.as_ptr() as *const File).add(n as usize) }
+    }
+
+    pub fn as_file_mut(&mut self, n: u32) -> &mut File {
+        assert!(self.b_type == BlockType::File && n < FILE2BLK);
+        unsafe { &mut *(self.b_data.as_mut_ptr() as *mut File).add(n as usize) }
     }
 
     pub fn write_u32(&mut self, n: u32, value: u32) {
-        assert!(n % 4 == 0);
+        assert!(n < BLOCK_SIZE / 4);
         let value = value.to_le_bytes();
         (0..4).for_each(|i| self.b_data[n as usize * 4 + i] = value[i]);
     }
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+
+use crate::fs::{
+    File, FileType, SuperBlock, BLOCK_SIZE, BLOCK_SIZE_BIT, DINDIRECT_PTR_CNT, DIRECT_PTR_CNT,
+    FEATURE_DOUBLE_INDIRECT, FILE2BLK, FILE_STRUCT_SIZE, FS_MAGIC, INDIRECT_PTR_CNT,
+};
+
+/// A read-only view of an existing disk image
+pub struct Image {
+    data: Vec<u8>,
+    pub super_block: SuperBlock,
+    super_block_no: u32,
+}
+
+impl Image {
+    pub fn open(path: &str) -> std::io::Result<Image> {
+        Image::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> std::io::Result<Image> {
+        if data.len() < 2 * BLOCK_SIZE as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "image is too small to hold a superblock",
+            ));
+        }
+        let super_block_no = find_super_block(&data);
+        let start = super_block_no as usize * BLOCK_SIZE as usize;
+        let super_block = SuperBlock::from_bytes(&data[start..]).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "root directory has an invalid type")
+        })?;
+        // Everything that reads the bitmap trusts this layout; a bad magic is left to `check`
+        let block_cnt = super_block
+            .get_block_cnt()
+            .min((data.len() / BLOCK_SIZE as usize) as u32);
+        let bitmap_end = super_block.bitmap_start() as u64
+            + super_block.get_block_cnt().div_ceil(BLOCK_SIZE_BIT) as u64;
+        if super_block.get_magic() == FS_MAGIC
+            && (super_block.get_boot_cnt() != super_block_no || bitmap_end > block_cnt as u64)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "superblock boot area and bitmap do not fit the image",
+            ));
+        }
+        Ok(Image {
+            data,
+            super_block,
+            super_block_no,
+        })
+    }
+
+    /// Block the superblock was read from
+    pub fn super_block_no(&self) -> u32 {
+        self.super_block_no
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of whole blocks actually present in the image
+    pub fn block_count(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE as usize) as u32
+    }
+
+    pub fn block(&self, n: u32) -> &[u8] {
+        let start = n as usize * BLOCK_SIZE as usize;
+        &self.data[start..start + BLOCK_SIZE as usize]
+    }
+
+    pub fn read_u32(&self, block: u32, n: u32) -> u32 {
+        let data = &self.block(block)[n as usize * 4..n as usize * 4 + 4];
+        u32::from_le_bytes(data.try_into().unwrap())
+    }
+
+    /// Number of blocks occupied by the bitmap, following `s_block_cnt`
+    pub fn bitmap_block_cnt(&self) -> u32 {
+        self.super_block.get_block_cnt().div_ceil(BLOCK_SIZE_BIT)
+    }
+
+    /// Whether block `n` is marked free in the bitmap
+    pub fn is_free(&self, n: u32) -> bool {
+        let word = self.read_u32(
+            self.super_block.bitmap_start() + n / (BLOCK_SIZE_BIT),
+            n % (BLOCK_SIZE_BIT) / 32,
+        );
+        word & (1 << (n % 32)) != 0
+    }
+
+    /// Block number holding the `n`th block of `file`, or 0 if there is none
+    pub fn file_block(&self, file: &File, n: u32) -> u32 {
+        if n < DIRECT_PTR_CNT {
+            file.get_direct(n)
+        } else if n < INDIRECT_PTR_CNT {
+            self.pointer(file.get_indirect(), n)
+        } else if self.double_indirect() && n - INDIRECT_PTR_CNT < DINDIRECT_PTR_CNT {
+            let n = n - INDIRECT_PTR_CNT;
+            let index = self.pointer(file.get_dindirect(), n / INDIRECT_PTR_CNT);
+            self.pointer(index, n % INDIRECT_PTR_CNT)
+        } else {
+            0
+        }
+    }
+
+    /// Entry `n` of index block `block`, or 0 if the index block does not exist
+    pub fn pointer(&self, block: u32, n: u32) -> u32 {
+        match block {
+            0 => 0,
+            block if block >= self.block_count() => 0,
+            block => self.read_u32(block, n),
+        }
+    }
+
+    /// Whether files may use `f_dindirect`
+    pub fn double_indirect(&self) -> bool {
+        self.super_block.has_feature(FEATURE_DOUBLE_INDIRECT)
+    }
+
+    /// Index blocks owned by `file`: its indirect block, then its double-indirect
+    /// block followed by the second-level blocks it points to
+    pub fn index_blocks(&self, file: &File) -> Vec<u32> {
+        let mut blocks = Vec::new();
+        if file.get_indirect() != 0 {
+            blocks.push(file.get_indirect());
+        }
+        if self.double_indirect() && file.get_dindirect() != 0 {
+            blocks.push(file.get_dindirect());
+            for i in 0..INDIRECT_PTR_CNT {
+                let index = self.pointer(file.get_dindirect(), i);
+                if index != 0 {
+                    blocks.push(index);
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Raw bytes of every `File` slot in block `n`
+    pub fn file_slots(&self, n: u32) -> impl Iterator<Item = &[u8]> {
+        self.block(n)
+            .chunks(FILE_STRUCT_SIZE as usize)
+            .take(FILE2BLK as usize)
+    }
+
+    /// Entries of a directory, skipping free slots and records with an invalid type
+    pub fn read_dir(&self, dir: &File) -> Vec<File> {
+        let mut entries = Vec::new();
+        for i in 0..dir.get_size() / BLOCK_SIZE {
+            let block = self.file_block(dir, i);
+            if block == 0 || block >= self.block_count() {
+                continue;
+            }
+            for slot in self.file_slots(block) {
+                if slot[0] == 0 {
+                    continue;
+                }
+                if let Some(file) = File::from_bytes(slot) {
+                    entries.push(file);
+                }
+            }
+        }
+        entries
+    }
+
+    /// Note the blocks of directory `dir` in `visited`. Returns false if one was seen
+    /// before, which in a corrupt image means a walk would loop through it forever.
+    pub fn visit_dir(&self, dir: &File, visited: &mut HashSet<u32>) -> bool {
+        let mut fresh = true;
+        for i in 0..dir.get_size() / BLOCK_SIZE {
+            let block = self.file_block(dir, i);
+            fresh &= block == 0 || visited.insert(block);
+        }
+        fresh
+    }
+
+    /// Contents of a regular file, cut to its `f_size`. Holes read as zeros.
+    pub fn read_file(&self, file: &File) -> Vec<u8> {
+        let size = file.get_size() as usize;
+        let mut data = Vec::with_capacity(size);
+        for i in 0..file.get_size().div_ceil(BLOCK_SIZE) {
+            let block = self.file_block(file, i);
+            let len = std::cmp::min(BLOCK_SIZE as usize, size - data.len());
+            if block == 0 || block >= self.block_count() {
+                data.resize(data.len() + len, 0);
+            } else {
+                data.extend_from_slice(&self.block(block)[..len]);
+            }
+        }
+        data
+    }
+
+    /// Find the `File` record at an absolute path such as `/bin/ls`
+    pub fn lookup(&self, path: &str) -> std::io::Result<File> {
+        let mut file = self.super_block.s_root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if file.get_type() != FileType::Directory {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("'{}': not a directory", path),
+                ));
+            }
+            file = self
+                .read_dir(&file)
+                .into_iter()
+                .find(|entry| entry.get_name() == name)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("'{}': no such file or directory", path),
+                    )
+                })?;
+        }
+        Ok(file)
+    }
+}
+
+/// Block holding the superblock: block 1, unless a reserved boot area pushes it further.
+/// A superblock found later must record a boot area that ends right before it.
+fn find_super_block(data: &[u8]) -> u32 {
+    let word = |n: usize| u32::from_le_bytes(data[n..n + 4].try_into().unwrap());
+    let block_count = data.len() / BLOCK_SIZE as usize;
+    (1..block_count)
+        .find(|&n| {
+            let start = n * BLOCK_SIZE as usize;
+            word(start) == FS_MAGIC
+                && SuperBlock::from_bytes(&data[start..])
+                    .is_some_and(|super_block| super_block.get_boot_cnt() as usize == n)
+        })
+        .unwrap_or(1) as u32
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Write};
+
+use crate::disk::join_path;
+use crate::fs::{File, FileType, BLOCK_SIZE};
+use crate::image::Image;
+
+/// Print `file` and, for directories, everything below it.
+/// Each line shows the type, size, block numbers and full path, plus the mode,
+/// owner and mtime when the image records them.
+pub fn list(image: &Image, file: &File, path: &str) {
+    list_file(image, file, path, &mut HashSet::new());
+}
+
+/// List `file`, recursing into directories whose blocks are not in `visited` yet
+fn list_file(image: &Image, file: &File, path: &str, visited: &mut HashSet<u32>) {
+    let mut kind = type_char(file.get_type()).to_string();
+    if image.super_block.has_metadata() {
+        kind = format!(
+            "{}{} {:>5} {:>5} {:>10}",
+            kind,
+            format_mode(file.get_mode()),
+            file.get_uid(),
+            file.get_gid(),
+            file.get_mtime()
+        );
+    }
+    let target = match file.get_type() {
+        FileType::Symlink => format!(" -> {}", String::from_utf8_lossy(&image.read_file(file))),
+        _ => String::new(),
+    };
+    let line = format_line(
+        &kind,
+        file.get_size(),
+        &file_blocks(image, file),
+        &image.index_blocks(file),
+        path,
+    );
+    println!("{}{}", line, target);
+
+    if file.get_type() != FileType::Directory {
+        return;
+    }
+    if !image.visit_dir(file, visited) {
+        eprintln!(
+            "Warning: '{}' reuses a directory block, not listing it",
+            path
+        );
+        return;
+    }
+    for entry in image.read_dir(file) {
+        let entry_path = join_path(path, &entry.get_name());
+        list_file(image, &entry, &entry_path, visited);
+    }
+}
+
+/// Write the contents of the regular file at `path` to stdout.
+/// Symlinks are not followed, so `cat` prints their target path.
+pub fn cat(image: &Image, path: &str) -> std::io::Result<()> {
+    let file = image.lookup(path)?;
+    if file.get_type() == FileType::Directory {
+        return Err(Error::new(
+            ErrorKind::IsADirectory,
+            format!("'{}': is a directory", path),
+        ));
+    }
+    std::io::stdout().write_all(&image.read_file(&file))
+}
+
+pub(crate) fn file_blocks(image: &Image, file: &File) -> Vec<u32> {
+    (0..file.get_size().div_ceil(BLOCK_SIZE))
+        .map(|i| image.file_block(file, i))
+        .filter(|&n| n != 0)
+        .collect()
+}
+
+/// Render permission bits the way `ls -l` does, e.g. `rwxr-xr-x`
+pub(crate) fn type_char(file_type: FileType) -> char {
+    match file_type {
+        FileType::File => '-',
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+    }
+}
+
+/// One `ls` line: the type column, size, data and index blocks, and path
+pub(crate) fn format_line(
+    kind: &str,
+    size: u32,
+    blocks: &[u32],
+    index: &[u32],
+    path: &str,
+) -> String {
+    let mut blocks = format_blocks(blocks);
+    if !index.is_empty() {
+        blocks = format!("{} (index {})", blocks, format_blocks(index));
+    }
+    format!("{} {:>10}  {:<24} {}", kind, size, blocks, path)
+}
+
+fn format_mode(mode: u32) -> String {
+    let special = [(0o4000, 6, 's'), (0o2000, 3, 's'), (0o1000, 0, 't')];
+    let mut out: Vec<char> = (0..9)
+        .map(|i| match mode & (0o400 >> i) != 0 {
+            true => ['r', 'w', 'x'][i % 3],
+            false => '-',
+        })
+        .collect();
+    for (bit, shift, c) in special {
+        if mode & bit != 0 {
+            let i = 8 - shift;
+            out[i] = match out[i] {
+                'x' => c,
+                _ => c.to_ascii_uppercase(),
+            };
+        }
+    }
+    out.into_iter().collect()
+}
+
+/// Compress a block list into ranges, e.g. `5-8,12`
+pub(crate) fn format_blocks(blocks: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &n in blocks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == n => *end = n,
+            _ => ranges.push((n, n)),
+        }
+    }
+    if ranges.is_empty() {
+        return "-".to_string();
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}-{}", start, end),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,24 @@
+//! Build, inspect and check MOS file system images.
+//!
+//! [`DiskImage`] builds an image from host paths or in-memory buffers,
+//! and [`Image`] reads an existing one back.
+
+pub mod check;
+pub mod crc;
+pub mod diff;
+pub mod disk;
+pub mod extract;
+pub mod filter;
+pub mod fs;
+pub mod image;
+pub mod inspect;
+pub mod manifest;
+pub mod partition;
+pub mod plan;
+pub mod qcow2;
+pub mod tar;
+pub mod usage;
+pub mod verify;
+
+pub use disk::{DiskError, DiskImage};
+pub use image::Image;
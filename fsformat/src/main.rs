@@ -1,35 +1,677 @@
-mod fs;
-mod disk;
+use std::{
+    env,
+    io::{Read, Write},
+    mem::size_of,
+    path::Path,
+};
 
-use std::{env, fs::File, mem::size_of};
+use fsformat::check::Checker;
+use fsformat::disk::{MetadataOverrides, DEFAULT_BLOCK_COUNT};
+use fsformat::filter::Filter;
+use fsformat::fs::{self, BLOCK_SIZE, BLOCK_SIZE_BIT};
+use fsformat::manifest::Manifest;
+use fsformat::partition::{self, Layout, Table};
+use fsformat::usage::Usage;
+use fsformat::{diff, extract, inspect, qcow2, tar, verify, DiskError, DiskImage, Image};
 
-use crate::disk::{disk_mut, finish_fs, flush_bitmap, init_disk};
+fn usage() -> ! {
+    eprintln!("Usage: fsformat [options] <img-file> [files or directories]...");
+    eprintln!("       fsformat add <img-file> <target-dir> [files or directories]...");
+    eprintln!("       fsformat rm [-r] <img-file> <path>...");
+    eprintln!("       fsformat mv <img-file> <from> <to>");
+    eprintln!("       fsformat mkdir [-p] <img-file> <path>...");
+    eprintln!("       fsformat compact [--shrink] <img-file> [out-file]");
+    eprintln!("       fsformat check <img-file>");
+    eprintln!("       fsformat extract <img-file> <out-dir>");
+    eprintln!("       fsformat ls <img-file> [path]");
+    eprintln!("       fsformat tar <img-file> <out.tar|->");
+    eprintln!("       fsformat cat <img-file> <path>");
+    eprintln!("       fsformat diff [--machine] <old-img> <new-img>");
+    eprintln!("       fsformat usage <img-file>");
+    eprintln!("       fsformat verify <img-file>");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!(
+        "  --blocks <n>         image size in blocks (default {})",
+        DEFAULT_BLOCK_COUNT
+    );
+    eprintln!(
+        "  --size <n>[K|M|G]    image size in bytes, a multiple of {}",
+        BLOCK_SIZE
+    );
+    eprintln!("  --checksums          keep a CRC-32 of every used block, for 'fsformat verify'");
+    eprintln!("  --boot <file>        load a bootloader or kernel into the boot area");
+    eprintln!("  --boot-reserve <n>   reserve n more blocks after block 0 for --boot; the");
+    eprintln!("                       superblock and bitmap move after them");
+    eprintln!("  --format <raw|qcow2> output format (default raw); qcow2 stores only used blocks");
+    eprintln!("  --partition-table <mbr|gpt>");
+    eprintln!(
+        "                       write a partitioned disk with the file system in a partition"
+    );
+    eprintln!("  --fs-offset <n>[K|M|G]");
+    eprintln!("                       byte offset of the file system partition (default 1M, or");
+    eprintln!("                       after the raw partition)");
+    eprintln!("  --raw-partition <file>");
+    eprintln!("                       add a partition holding this file, such as a boot partition");
+    eprintln!("  --tar <file|->       unpack a tar archive into the root (repeatable)");
+    eprintln!("  --manifest <file>    lay out files as described in a manifest (repeatable)");
+    eprintln!("  --host-order         keep host directory order instead of sorting by name");
+    eprintln!("  --double-indirect    allow files past 4 MiB (not readable by the stock kernel)");
+    eprintln!("  --preserve-symlinks  store host symlinks as links instead of following them");
+    eprintln!("  --sparse             leave all-zero blocks of files unallocated");
+    eprintln!("  --dedup              let files with identical contents share data blocks");
+    eprintln!("  --dry-run            print the planned layout and every problem, write nothing");
+    eprintln!("  --max-fill <percent> fail if more than this share of the blocks would be used");
+    eprintln!("  --include <glob>     only copy host files matching a pattern (repeatable)");
+    eprintln!("  --exclude <glob>     skip host entries matching a pattern (repeatable)");
+    eprintln!("  --fsignore           skip entries listed in .fsignore files of host directories");
+    eprintln!("  --verbose            report every skipped host entry and why");
+    eprintln!("  --metadata           record host mode, owner and mtime (format version 2)");
+    eprintln!("  --mode <octal>       record this mode for every file (implies --metadata)");
+    eprintln!("  --dir-mode <octal>   record this mode for every directory (implies --metadata)");
+    eprintln!("  --uid <n>, --gid <n> record this owner for every entry (implies --metadata)");
+    eprintln!("  --mtime <seconds>    record this mtime for every entry (implies --metadata)");
+    eprintln!();
+    eprintln!("Exit status:");
+    eprintln!("  1 usage error or inconsistent image, 2 I/O error, 3 non-UTF-8 name,");
+    eprintln!("  4 name too long, 5 file too large, 6 unsupported file type, 7 out of blocks,");
+    eprintln!("  8 no such directory in the image, 9 bad manifest, 10 bad symlink target,");
+    eprintln!("  11 image fuller than --max-fill, 12 no such file in the image,");
+    eprintln!("  13 file already exists, 14 directory not empty, 15 invalid image path,");
+    eprintln!("  16 bad tar archive, 17 boot payload larger than the boot area,");
+    eprintln!("  18 bad partition layout");
+    eprintln!();
+    eprintln!("Reading commands also accept qcow2 images and partitioned disks, using the");
+    eprintln!("MOS partition of the latter.");
+    std::process::exit(1);
+}
 
 fn main() {
     assert!(size_of::<fs::File>() == fs::FILE_STRUCT_SIZE as usize);
-    init_disk();
+    let args: Vec<String> = env::args_os()
+        .map(|arg| {
+            arg.into_string().unwrap_or_else(|arg| {
+                fail(DiskError::NonUtf8Name {
+                    path: arg.to_string_lossy().into_owned(),
+                })
+            })
+        })
+        .collect();
+    if args.len() < 3 {
+        usage();
+    }
+
+    match args[1].as_str() {
+        "add" => add(&args[2..]),
+        "rm" => rm(&args[2..]),
+        "mv" => mv(&args[2..]),
+        "mkdir" => mkdir(&args[2..]),
+        "compact" => compact(&args[2..]),
+        "check" => check(&args[2..]),
+        "extract" => extract(&args[2..]),
+        "ls" => ls(&args[2..]),
+        "tar" => export_tar(&args[2..]),
+        "cat" => cat(&args[2..]),
+        "diff" => diff(&args[2..]),
+        "usage" => report_usage(&args[2..]),
+        "verify" => verify(&args[2..]),
+        _ => build(&args[1..]),
+    }
+}
+
+/// Read an image, possibly in qcow2 format, or the MOS partition of a partitioned disk
+fn open_image(path: &str) -> Image {
+    let result = std::fs::read(path)
+        .and_then(|data| match qcow2::is_qcow2(&data) {
+            true => qcow2::read(&data),
+            false => Ok(data),
+        })
+        .and_then(|data| match partition::find_filesystem(&data) {
+            Some(range) => Image::from_bytes(data[range].to_vec()),
+            None => Image::from_bytes(data),
+        });
+    match result {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error: cannot read '{}': {}", path, e);
+            std::process::exit(2);
+        }
+    }
+}
 
-    if env::args().count() < 3 {
-        eprintln!("Usage: fsformat <img-file> [files or directories]...");
+fn check(args: &[String]) {
+    if args.len() != 1 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    let problems = Checker::new(&image).check();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        println!("{}: {} problem(s) found", &args[0], problems.len());
         std::process::exit(1);
     }
+    println!("{}: clean", &args[0]);
+}
 
-    for i in 2..env::args().count() {
-        let path = env::args().nth(i).unwrap();
-        let file = File::open(&path).unwrap();
-        if file.metadata().unwrap().is_dir() {
-            println!("Writing directory '{}' recursively into disk image", &path);
-            disk_mut().write_dir(&mut disk_mut().super_block.s_root, &path);
-        } else if file.metadata().unwrap().is_file() {
-            println!("Writing file '{}' into disk image", &path);
-            disk_mut().write_file(&mut disk_mut().super_block.s_root, &path);
-        } else {
-            eprintln!("Error: '{}' is not of supported type", &path);
+fn extract(args: &[String]) {
+    if args.len() != 2 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    if let Err(e) = extract::extract(&image, &args[1]) {
+        eprintln!("Error: cannot extract into '{}': {}", &args[1], e);
+        std::process::exit(2);
+    }
+}
+
+fn export_tar(args: &[String]) {
+    if args.len() != 2 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    let result = match args[1].as_str() {
+        "-" => tar::export(&image, &mut std::io::stdout().lock()),
+        path => std::fs::File::create(path)
+            .map(std::io::BufWriter::new)
+            .and_then(|mut out| {
+                tar::export(&image, &mut out).and_then(|_| out.into_inner()?.sync_all())
+            }),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: cannot write '{}': {}", &args[1], e);
+        std::process::exit(2);
+    }
+}
+
+fn ls(args: &[String]) {
+    if args.is_empty() || args.len() > 2 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    let path = args.get(1).map_or("/", String::as_str);
+    match image.lookup(path) {
+        Ok(file) => inspect::list(&image, &file, path),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn cat(args: &[String]) {
+    if args.len() != 2 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    if let Err(e) = inspect::cat(&image, &args[1]) {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    }
+}
+
+/// Print the differences between two images; exits with 1 if there are any, like `cmp`
+fn diff(args: &[String]) {
+    let (machine, args) = match args.first().map(String::as_str) {
+        Some("--machine") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    if args.len() != 2 {
+        usage();
+    }
+    let differences = diff::diff(&open_image(&args[0]), &open_image(&args[1]));
+    for difference in &differences {
+        match machine {
+            true => println!("{}", difference.to_machine()),
+            false => println!("{}", difference),
+        }
+    }
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Check every used block against the checksum table; exits with 1 on a mismatch
+fn verify(args: &[String]) {
+    if args.len() != 1 {
+        usage();
+    }
+    let image = open_image(&args[0]);
+    let corruptions = verify::verify(&image).unwrap_or_else(|reason| {
+        eprintln!("Error: '{}': {}", &args[0], reason);
+        std::process::exit(1);
+    });
+    for corruption in &corruptions {
+        println!("{}", corruption);
+    }
+    if !corruptions.is_empty() {
+        println!("{}: {} corrupted block(s)", &args[0], corruptions.len());
+        std::process::exit(1);
+    }
+    println!("{}: all blocks match their checksums", &args[0]);
+}
+
+fn report_usage(args: &[String]) {
+    if args.len() != 1 {
+        usage();
+    }
+    let usage = Usage::of(&open_image(&args[0]));
+    usage.print_summary();
+    println!();
+    usage.print_dirs();
+}
+
+/// Read an image that is about to be rewritten, refusing one that fails the consistency check
+fn open_checked(path: &str) -> Image {
+    let image = match Image::open(path) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Error: cannot read '{}': {}", path, e);
             std::process::exit(2);
         }
+    };
+    if qcow2::is_qcow2(image.as_bytes()) || partition::find_filesystem(image.as_bytes()).is_some() {
+        eprintln!(
+            "Error: '{}' is a qcow2 image or partitioned disk, rebuild it to change the file system",
+            path
+        );
+        std::process::exit(2);
+    }
+    let problems = Checker::new(&image).check();
+    if !problems.is_empty() {
+        eprintln!(
+            "Error: '{}' is inconsistent, run 'fsformat check' first",
+            path
+        );
+        std::process::exit(2);
+    }
+    image
+}
+
+/// Load an existing image for editing
+fn open_disk(path: &str) -> DiskImage {
+    match DiskImage::from_image(&open_checked(path)) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Error: cannot load '{}': {}", path, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn add(args: &[String]) {
+    if args.len() < 3 {
+        usage();
+    }
+    let mut disk = open_disk(&args[0]);
+    write_image(&mut disk, &args[1], &args[2..], &args[0], None).unwrap_or_else(|e| fail(e));
+}
+
+fn rm(args: &[String]) {
+    let (recursive, args) = match args.first().map(String::as_str) {
+        Some("-r") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let mut disk = open_disk(&args[0]);
+    for path in &args[1..] {
+        disk.remove(path, recursive).unwrap_or_else(|e| fail(e));
+    }
+    save_image(&mut disk, &args[0], None).unwrap_or_else(|e| fail(e));
+}
+
+fn mv(args: &[String]) {
+    if args.len() != 3 {
+        usage();
+    }
+    let mut disk = open_disk(&args[0]);
+    disk.rename(&args[1], &args[2]).unwrap_or_else(|e| fail(e));
+    save_image(&mut disk, &args[0], None).unwrap_or_else(|e| fail(e));
+}
+
+fn mkdir(args: &[String]) {
+    let (parents, args) = match args.first().map(String::as_str) {
+        Some("-p") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    if args.len() < 2 {
+        usage();
+    }
+    let mut disk = open_disk(&args[0]);
+    for path in &args[1..] {
+        disk.make_directory(path, parents)
+            .unwrap_or_else(|e| fail(e));
+    }
+    save_image(&mut disk, &args[0], None).unwrap_or_else(|e| fail(e));
+}
+
+fn compact(args: &[String]) {
+    let (shrink, args) = match args.first().map(String::as_str) {
+        Some("--shrink") => (true, &args[1..]),
+        _ => (false, args),
+    };
+    if args.is_empty() || args.len() > 2 {
+        usage();
+    }
+    let image = open_checked(&args[0]);
+    let mut disk = DiskImage::compact(&image, shrink).unwrap_or_else(|e| fail(e));
+    if disk.block_cnt() != image.super_block.get_block_cnt() {
+        println!(
+            "Shrinking from {} to {} blocks",
+            image.super_block.get_block_cnt(),
+            disk.block_cnt()
+        );
+    }
+    let out = args.get(1).unwrap_or(&args[0]);
+    save_image(&mut disk, out, None).unwrap_or_else(|e| fail(e));
+}
+
+fn build(args: &[String]) {
+    let mut block_count = None;
+    let mut manifests = Vec::new();
+    let mut archives = Vec::new();
+    let mut host_order = false;
+    let mut double_indirect = false;
+    let mut preserve_symlinks = false;
+    let mut sparse = false;
+    let mut dedup = false;
+    let mut max_fill = None;
+    let mut dry_run = false;
+    let mut boot = None;
+    let mut boot_reserve = 0;
+    let mut table = None;
+    let mut fs_offset = None;
+    let mut raw_partition = None;
+    let mut qcow2 = false;
+    let mut checksums = false;
+    let mut metadata: Option<MetadataOverrides> = None;
+    let mut filter = Filter::default();
+    let mut args = args.iter();
+    let img = loop {
+        match args.next().map(String::as_str) {
+            Some("--blocks") if block_count.is_none() => {
+                block_count = args.next().and_then(|n| n.parse::<u64>().ok());
+                if block_count.is_none() {
+                    usage();
+                }
+            }
+            Some("--size") if block_count.is_none() => {
+                block_count = match args.next().and_then(|n| parse_size(n)) {
+                    Some(size) if size % BLOCK_SIZE as u64 == 0 => Some(size / BLOCK_SIZE as u64),
+                    _ => usage(),
+                };
+            }
+            Some("--host-order") => host_order = true,
+            Some("--double-indirect") => double_indirect = true,
+            Some("--preserve-symlinks") => preserve_symlinks = true,
+            Some("--sparse") => sparse = true,
+            Some("--dedup") => dedup = true,
+            Some("--dry-run") => dry_run = true,
+            Some("--checksums") => checksums = true,
+            Some("--max-fill") => {
+                max_fill = args.next().and_then(|n| n.parse::<u32>().ok());
+                if !matches!(max_fill, Some(0..=100)) {
+                    usage();
+                }
+            }
+            Some("--metadata") => {
+                metadata.get_or_insert_with(MetadataOverrides::default);
+            }
+            Some(option @ ("--mode" | "--dir-mode" | "--uid" | "--gid" | "--mtime")) => {
+                let value = match option {
+                    "--mode" | "--dir-mode" => args
+                        .next()
+                        .and_then(|n| u32::from_str_radix(n, 8).ok())
+                        .filter(|&mode| mode <= fs::MODE_MASK),
+                    _ => args.next().and_then(|n| n.parse::<u32>().ok()),
+                };
+                let Some(value) = value else { usage() };
+                let overrides = metadata.get_or_insert_with(MetadataOverrides::default);
+                match option {
+                    "--mode" => overrides.file_mode = Some(value),
+                    "--dir-mode" => overrides.dir_mode = Some(value),
+                    "--uid" => overrides.uid = Some(value),
+                    "--gid" => overrides.gid = Some(value),
+                    _ => overrides.mtime = Some(value),
+                }
+            }
+            Some("--include") => match args.next() {
+                Some(pattern) => filter.include(pattern),
+                None => usage(),
+            },
+            Some("--exclude") => match args.next() {
+                Some(pattern) => filter.exclude(pattern),
+                None => usage(),
+            },
+            Some("--fsignore") => filter.fsignore = true,
+            Some("--verbose") => filter.verbose = true,
+            Some("--boot") => match args.next() {
+                Some(path) => boot = Some(path.as_str()),
+                None => usage(),
+            },
+            Some("--boot-reserve") => match args.next().and_then(|n| n.parse::<u32>().ok()) {
+                Some(n) => boot_reserve = n,
+                None => usage(),
+            },
+            Some("--partition-table") => match args.next().map(String::as_str) {
+                Some("mbr") => table = Some(Table::Mbr),
+                Some("gpt") => table = Some(Table::Gpt),
+                _ => usage(),
+            },
+            Some("--fs-offset") => match args.next().and_then(|n| parse_size(n)) {
+                Some(offset) => fs_offset = Some(offset),
+                None => usage(),
+            },
+            Some("--raw-partition") => match args.next() {
+                Some(path) => raw_partition = Some(path.as_str()),
+                None => usage(),
+            },
+            Some("--format") => match args.next().map(String::as_str) {
+                Some("raw") => qcow2 = false,
+                Some("qcow2") => qcow2 = true,
+                _ => usage(),
+            },
+            Some("--tar") => match args.next() {
+                Some(path) => archives.push(path.as_str()),
+                None => usage(),
+            },
+            Some("--manifest") => match args.next() {
+                Some(path) => manifests.push(Manifest::open(path).unwrap_or_else(|e| fail(e))),
+                None => usage(),
+            },
+            Some(arg) if !arg.starts_with("--") => break arg,
+            _ => usage(),
+        }
+    };
+    let paths: Vec<String> = args.cloned().collect();
+    if paths.is_empty() && manifests.is_empty() && archives.is_empty() {
+        usage();
+    }
+    if table.is_none() && (fs_offset.is_some() || raw_partition.is_some()) {
+        usage();
+    }
+    let layout = table.map(|table| Layout {
+        table,
+        fs_offset,
+        raw: raw_partition.map(|path| read_input(path).unwrap_or_else(|e| fail(e))),
+    });
+
+    let block_count = block_count.unwrap_or(DEFAULT_BLOCK_COUNT as u64);
+    let boot_cnt = 1 + boot_reserve as u64;
+    let min_count = boot_cnt + 2 + (block_count.div_ceil(BLOCK_SIZE_BIT as u64));
+    if block_count < min_count || block_count > u32::MAX as u64 {
+        eprintln!(
+            "Error: image size must be between {} and {} blocks",
+            min_count,
+            u32::MAX
+        );
+        std::process::exit(1);
+    }
+    let mut disk = DiskImage::with_boot_area(block_count as u32, boot_cnt as u32);
+    if let Some(path) = boot {
+        let data = read_input(path).unwrap_or_else(|e| fail(e));
+        disk.set_boot_payload(&data, path)
+            .unwrap_or_else(|e| fail(e));
+    }
+    if checksums {
+        disk.enable_checksums().unwrap_or_else(|e| fail(e));
+    }
+    disk.set_host_order(host_order);
+    disk.set_preserve_symlinks(preserve_symlinks);
+    disk.set_sparse(sparse);
+    disk.set_dedup(dedup);
+    disk.set_filter(filter);
+    disk.set_dry_run(dry_run);
+    if let Some(overrides) = metadata {
+        disk.enable_metadata(overrides);
+    }
+    if double_indirect {
+        disk.enable_double_indirect();
+    }
+    for path in archives {
+        println!("Unpacking archive '{}' into disk image", path);
+        let data = read_input(path).unwrap_or_else(|e| fail(e));
+        tar::import(&mut disk, &data, path).unwrap_or_else(|e| fail(e));
+    }
+    for manifest in &manifests {
+        manifest.apply(&mut disk).unwrap_or_else(|e| fail(e));
+    }
+    if dry_run {
+        add_paths(&mut disk, "/", &paths).unwrap_or_else(|e| fail(e));
+        report_plan(&mut disk, img, max_fill);
+    }
+    add_paths(&mut disk, "/", &paths).unwrap_or_else(|e| fail(e));
+    save_wrapped(&mut disk, img, max_fill, layout.as_ref(), qcow2).unwrap_or_else(|e| fail(e));
+    if sparse {
+        println!(
+            "Sparse files saved {} bytes ({} blocks)",
+            disk.sparse_saved(),
+            disk.sparse_saved() / BLOCK_SIZE as u64
+        );
+    }
+}
+
+/// Read a whole host file, or stdin for `-`
+fn read_input(path: &str) -> Result<Vec<u8>, DiskError> {
+    let mut data = Vec::new();
+    let result = match path {
+        "-" => std::io::stdin().read_to_end(&mut data).map(|_| ()),
+        path => std::fs::read(path).map(|contents| data = contents),
+    };
+    result.map(|_| data).map_err(|e| DiskError::Io {
+        path: path.to_string(),
+        source: e,
+    })
+}
+
+/// Parse a byte count with an optional K, M or G suffix
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn write_image(
+    disk: &mut DiskImage,
+    dir: &str,
+    paths: &[String],
+    img: &str,
+    max_fill: Option<u32>,
+) -> Result<(), DiskError> {
+    add_paths(disk, dir, paths)?;
+    save_image(disk, img, max_fill)
+}
+
+fn add_paths(disk: &mut DiskImage, dir: &str, paths: &[String]) -> Result<(), DiskError> {
+    for path in paths {
+        if Path::new(path).is_dir() {
+            println!("Writing directory '{}' recursively into disk image", path);
+        } else {
+            println!("Writing file '{}' into disk image", path);
+        }
+        disk.add_path(dir, path)?;
+    }
+    Ok(())
+}
+
+/// Print the layout a dry run planned and exit with the status the real build would have
+fn report_plan(disk: &mut DiskImage, img: &str, max_fill: Option<u32>) -> ! {
+    let plan = disk.plan();
+    plan.print();
+    if let Some(violation) = plan.violations.first() {
+        std::process::exit(violation.exit_code());
+    }
+    if !plan.fits() {
+        fail(DiskError::OutOfBlocks {
+            path: img.to_string(),
+            block_cnt: plan.block_cnt,
+        });
+    }
+    if let Some(max_fill) = max_fill {
+        if plan.needed as u64 * 100 > max_fill as u64 * plan.block_cnt as u64 {
+            fail(DiskError::TooFull {
+                path: img.to_string(),
+                used: plan.needed,
+                block_cnt: plan.block_cnt,
+                max_fill,
+            });
+        }
     }
+    std::process::exit(0);
+}
 
-    flush_bitmap();
-    finish_fs(env::args().nth(1).unwrap().as_str());
+/// Print the usage summary and write the image, unless it is fuller than `max_fill` percent
+fn save_image(disk: &mut DiskImage, img: &str, max_fill: Option<u32>) -> Result<(), DiskError> {
+    save_wrapped(disk, img, max_fill, None, false)
 }
 
+/// Like `save_image`, placing the file system in a partitioned disk laid out by `layout`,
+/// and writing qcow2 instead of a raw image with `qcow2`
+fn save_wrapped(
+    disk: &mut DiskImage,
+    img: &str,
+    max_fill: Option<u32>,
+    layout: Option<&Layout>,
+    qcow2: bool,
+) -> Result<(), DiskError> {
+    let image = disk.to_image();
+    let usage = Usage::of(&image);
+    usage.print_summary();
+    if let Some(max_fill) = max_fill {
+        if usage.used() as u64 * 100 > max_fill as u64 * usage.block_cnt as u64 {
+            return Err(DiskError::TooFull {
+                path: img.to_string(),
+                used: usage.used(),
+                block_cnt: usage.block_cnt,
+                max_fill,
+            });
+        }
+    }
+    let wrapped = match layout {
+        Some(layout) => Some(layout.wrap(image.as_bytes(), img)?),
+        None => None,
+    };
+    let data = wrapped.as_deref().unwrap_or(image.as_bytes());
+    let result = match qcow2 {
+        true => std::fs::File::create(img)
+            .map(std::io::BufWriter::new)
+            .and_then(|mut out| qcow2::write(data, &mut out).and_then(|_| out.flush())),
+        false => std::fs::write(img, data),
+    };
+    result.map_err(|e| DiskError::Io {
+        path: img.to_string(),
+        source: e,
+    })
+}
+
+fn fail(e: DiskError) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(e.exit_code());
+}
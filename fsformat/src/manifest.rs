@@ -0,0 +1,197 @@
+//! Declarative image layout.
+//!
+//! A manifest has one entry per line; `#` starts a comment:
+//!
+//! ```text
+//! mkdir  /etc
+//! copy   build/user/ls     /bin/ls
+//! copy   rootfs/test       /test
+//! inline /etc/motd         "Welcome to MOS\n"
+//! link   /bin/sh           /bin/ls
+//! ```
+//!
+//! `copy` places a host file or directory at an image path, so it can also rename it.
+//! `inline` writes the given text into a file. `link` creates a symlink at its first
+//! path pointing to the second, which is stored as written. Missing parent directories are created.
+//! Relative host paths are resolved against the manifest's own directory.
+//! Tokens may be double-quoted, with `\n`, `\t`, `\\`, `\"` and `\xHH` escapes.
+
+use std::path::Path;
+
+use crate::disk::{split_path, DiskError, DiskImage};
+
+pub enum Entry {
+    Mkdir { dest: String },
+    Copy { source: String, dest: String },
+    Inline { dest: String, data: Vec<u8> },
+    Link { dest: String, target: String },
+}
+
+/// A parsed manifest, checked for syntax errors before anything is written
+pub struct Manifest {
+    path: String,
+    entries: Vec<(usize, Entry)>,
+}
+
+impl Manifest {
+    pub fn open(path: &str) -> Result<Manifest, DiskError> {
+        let text = std::fs::read_to_string(path).map_err(|e| DiskError::Io {
+            path: path.to_string(),
+            source: e,
+        })?;
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        Manifest::parse(&text, base, path)
+    }
+
+    /// Parse manifest text; relative host paths are resolved against `base`
+    pub fn parse(text: &str, base: &Path, path: &str) -> Result<Manifest, DiskError> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let error = |reason: String| DiskError::BadManifest {
+                path: path.to_string(),
+                line: i + 1,
+                reason,
+            };
+            let tokens = tokenize(line).map_err(error)?;
+            let entry = match tokens.iter().map(|t| t.as_slice()).collect::<Vec<_>>()[..] {
+                [] => continue,
+                [b"mkdir", dest] => Entry::Mkdir {
+                    dest: dest_path(dest, true).map_err(error)?,
+                },
+                [b"copy", source, dest] => Entry::Copy {
+                    source: base
+                        .join(utf8(source).map_err(error)?)
+                        .to_string_lossy()
+                        .into_owned(),
+                    dest: dest_path(dest, false).map_err(error)?,
+                },
+                [b"inline", dest, data] => Entry::Inline {
+                    dest: dest_path(dest, false).map_err(error)?,
+                    data: data.to_vec(),
+                },
+                [b"link", dest, target] => Entry::Link {
+                    dest: dest_path(dest, false).map_err(error)?,
+                    target: utf8(target).map_err(error)?.to_string(),
+                },
+                _ => return Err(error(format!("cannot parse '{}'", line.trim()))),
+            };
+            entries.push((i + 1, entry));
+        }
+        Ok(Manifest {
+            path: path.to_string(),
+            entries,
+        })
+    }
+
+    /// Apply every entry to `disk`, in order.
+    /// A dry run records entries that cannot be stored and carries on.
+    pub fn apply(&self, disk: &mut DiskImage) -> Result<(), DiskError> {
+        for (line, entry) in &self.entries {
+            let result = match entry {
+                Entry::Mkdir { dest } => disk.create_dir_all(dest),
+                Entry::Copy { source, dest } => {
+                    // dest_path keeps the root out of everything but mkdir
+                    let (dir, name) = split_path(dest);
+                    let name = name.unwrap();
+                    disk.create_dir_all(dir)
+                        .and_then(|_| disk.add_path_as(dir, name, source))
+                }
+                Entry::Inline { dest, data } => {
+                    let (dir, name) = split_path(dest);
+                    let name = name.unwrap();
+                    let result = disk
+                        .create_dir_all(dir)
+                        .and_then(|_| disk.add_file(dir, name, data));
+                    disk.recover(result).map_err(|e| match e {
+                        DiskError::FileTooLarge { .. } => DiskError::BadManifest {
+                            path: self.path.clone(),
+                            line: *line,
+                            reason: format!("inline contents of '{}' are too large", dest),
+                        },
+                        e => e,
+                    })
+                }
+                Entry::Link { dest, target } => {
+                    let (dir, name) = split_path(dest);
+                    let name = name.unwrap();
+                    disk.create_dir_all(dir)
+                        .and_then(|_| disk.add_symlink(dir, name, target))
+                }
+            };
+            disk.recover(result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split a line into tokens, honouring quotes, escapes and `#` comments
+fn tokenize(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut token = Vec::new();
+            loop {
+                match chars.next() {
+                    None => return Err("unterminated quoted string".to_string()),
+                    Some('"') => break,
+                    Some('\\') => token.push(unescape(&mut chars)?),
+                    Some(c) => token.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '#' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token.into_bytes());
+        }
+    }
+    Ok(tokens)
+}
+
+fn unescape(chars: &mut impl Iterator<Item = char>) -> Result<u8, String> {
+    match chars.next() {
+        Some('n') => Ok(b'\n'),
+        Some('t') => Ok(b'\t'),
+        Some('0') => Ok(0),
+        Some('\\') => Ok(b'\\'),
+        Some('"') => Ok(b'"'),
+        Some('x') => {
+            let hex: String = chars.take(2).collect();
+            u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape '\\x{}'", hex))
+        }
+        Some(c) => Err(format!("unknown escape '\\{}'", c)),
+        None => Err("unterminated escape".to_string()),
+    }
+}
+
+fn utf8(token: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(token).map_err(|_| "path is not valid UTF-8".to_string())
+}
+
+/// Validate an absolute image path; only `mkdir` may name the root itself
+fn dest_path(token: &[u8], allow_root: bool) -> Result<String, String> {
+    let path = utf8(token)?;
+    if !path.starts_with('/') {
+        return Err(format!("image path '{}' is not absolute", path));
+    }
+    let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+    if names.iter().any(|&name| name == "." || name == "..") {
+        return Err(format!("image path '{}' contains '.' or '..'", path));
+    }
+    if names.is_empty() && !allow_root {
+        return Err("the root directory cannot be replaced".to_string());
+    }
+    Ok(format!("/{}", names.join("/")))
+}
@@ -0,0 +1,292 @@
+//! Partitioned disk images holding a MOS file system.
+//!
+//! `Layout::wrap` puts a file system image in a partition of an MBR or GPT disk, optionally
+//! next to a raw partition copied from a host file (a boot partition, typically).
+//! `find_filesystem` goes the other way and locates the MOS partition by its type.
+//!
+//! The MOS partition uses MBR type 0x7f, set aside for experimental systems, and a
+//! GPT type GUID of its own. The raw partition is marked FAT32 (LBA) or basic data,
+//! which is where board firmware looks for boot files. GUIDs other than the types
+//! are fixed, so a disk built from the same inputs is always the same.
+
+use std::ops::Range;
+
+use crate::crc::crc32;
+use crate::disk::DiskError;
+
+const SECTOR: u64 = 512;
+/// Partitions start on 1 MiB boundaries unless placed explicitly
+const ALIGN: u64 = 1 << 20;
+
+pub const MBR_TYPE_MOS: u8 = 0x7f;
+const MBR_TYPE_RAW: u8 = 0x0c;
+const MBR_TYPE_GPT: u8 = 0xee;
+
+pub const GPT_TYPE_MOS: &str = "6d6f7366-7300-4a5c-9b1e-3c5d2f0e8a41";
+const GPT_TYPE_RAW: &str = "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7";
+const GPT_DISK_GUID: &str = "6d6f7364-6973-4b00-8000-000000000000";
+const GPT_ENTRY_CNT: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+/// Sectors taken by the partition entry array
+const GPT_ENTRY_SECTORS: u64 = GPT_ENTRY_CNT * GPT_ENTRY_SIZE / SECTOR;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Mbr,
+    Gpt,
+}
+
+struct Partition<'a> {
+    data: &'a [u8],
+    start: u64,
+    mos: bool,
+}
+
+impl Partition<'_> {
+    fn sectors(&self) -> u64 {
+        (self.data.len() as u64).div_ceil(SECTOR)
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.sectors() * SECTOR
+    }
+}
+
+/// How to lay out a partitioned disk around a file system
+pub struct Layout {
+    pub table: Table,
+    /// Byte offset of the file system partition
+    pub fs_offset: Option<u64>,
+    /// Contents of the raw partition, if there is one
+    pub raw: Option<Vec<u8>>,
+}
+
+impl Layout {
+    /// Build a partitioned disk holding the file system `fs`. Without `fs_offset` the file
+    /// system goes on the first 1 MiB boundary after the raw partition; the raw partition
+    /// takes 1 MiB if it fits before the file system, and the next boundary after it
+    /// otherwise. `path` names the disk in errors.
+    pub fn wrap(&self, fs: &[u8], path: &str) -> Result<Vec<u8>, DiskError> {
+        wrap(self.table, fs, self.fs_offset, self.raw.as_deref(), path)
+    }
+}
+
+fn wrap(
+    table: Table,
+    fs: &[u8],
+    fs_offset: Option<u64>,
+    raw: Option<&[u8]>,
+    path: &str,
+) -> Result<Vec<u8>, DiskError> {
+    let error = |reason: String| DiskError::BadPartition {
+        path: path.to_string(),
+        reason,
+    };
+    let first_usable = match table {
+        Table::Mbr => SECTOR,
+        Table::Gpt => (2 + GPT_ENTRY_SECTORS) * SECTOR,
+    };
+    let raw_end = raw.map_or(ALIGN, |raw| ALIGN + raw.len() as u64);
+    let fs_start = fs_offset.unwrap_or(raw_end.next_multiple_of(ALIGN));
+    if !fs_start.is_multiple_of(SECTOR) || fs_start < first_usable {
+        return Err(error(format!(
+            "file system offset {} must be a multiple of {} and at least {}",
+            fs_start, SECTOR, first_usable
+        )));
+    }
+    let mut partitions = vec![Partition {
+        data: fs,
+        start: fs_start,
+        mos: true,
+    }];
+    if let Some(raw) = raw {
+        let start = match raw_end <= fs_start {
+            true => ALIGN,
+            false => (fs_start + fs.len() as u64).next_multiple_of(ALIGN),
+        };
+        partitions.push(Partition {
+            data: raw,
+            start,
+            mos: false,
+        });
+        partitions.sort_by_key(|p| p.start);
+    }
+
+    let end = partitions.last().unwrap().end();
+    let sector_cnt = match table {
+        Table::Mbr => end / SECTOR,
+        Table::Gpt => end / SECTOR + GPT_ENTRY_SECTORS + 1,
+    };
+    if table == Table::Mbr && sector_cnt > u32::MAX as u64 {
+        return Err(error(format!(
+            "{} sectors are past what an MBR can address",
+            sector_cnt
+        )));
+    }
+
+    let mut disk = vec![0; (sector_cnt * SECTOR) as usize];
+    for partition in &partitions {
+        let start = partition.start as usize;
+        disk[start..start + partition.data.len()].copy_from_slice(partition.data);
+    }
+    match table {
+        Table::Mbr => {
+            for (i, partition) in partitions.iter().enumerate() {
+                let kind = match partition.mos {
+                    true => MBR_TYPE_MOS,
+                    false => MBR_TYPE_RAW,
+                };
+                let start = partition.start / SECTOR;
+                mbr_entry(&mut disk, i, kind, start, partition.sectors());
+            }
+        }
+        Table::Gpt => {
+            // The protective MBR covers the whole disk, as far as it can
+            mbr_entry(
+                &mut disk,
+                0,
+                MBR_TYPE_GPT,
+                1,
+                (sector_cnt - 1).min(u32::MAX as u64),
+            );
+            let mut entries = vec![0; (GPT_ENTRY_CNT * GPT_ENTRY_SIZE) as usize];
+            for (i, partition) in partitions.iter().enumerate() {
+                let (kind, name) = match partition.mos {
+                    true => (GPT_TYPE_MOS, "MOS"),
+                    false => (GPT_TYPE_RAW, "boot"),
+                };
+                let entry = &mut entries[i * GPT_ENTRY_SIZE as usize..][..GPT_ENTRY_SIZE as usize];
+                let mut unique = guid(GPT_DISK_GUID);
+                unique[15] = i as u8 + 1;
+                entry[0..16].copy_from_slice(&guid(kind));
+                entry[16..32].copy_from_slice(&unique);
+                let first = partition.start / SECTOR;
+                entry[32..40].copy_from_slice(&first.to_le_bytes());
+                entry[40..48].copy_from_slice(&(first + partition.sectors() - 1).to_le_bytes());
+                for (j, unit) in name.encode_utf16().enumerate() {
+                    entry[56 + 2 * j..58 + 2 * j].copy_from_slice(&unit.to_le_bytes());
+                }
+            }
+            let last = sector_cnt - 1;
+            let backup_entries = last - GPT_ENTRY_SECTORS;
+            let usable = (2 + GPT_ENTRY_SECTORS, backup_entries - 1);
+            for (lba, other, entries_lba) in [(1, last, 2), (last, 1, backup_entries)] {
+                let header = gpt_header(lba, other, entries_lba, usable, &entries);
+                let start = (lba * SECTOR) as usize;
+                disk[start..start + header.len()].copy_from_slice(&header);
+                let start = (entries_lba * SECTOR) as usize;
+                disk[start..start + entries.len()].copy_from_slice(&entries);
+            }
+        }
+    }
+    disk[510] = 0x55;
+    disk[511] = 0xaa;
+    Ok(disk)
+}
+
+/// Byte range of the MOS partition, if `data` starts with a partition table listing one.
+/// The range is cut to the end of `data`.
+pub fn find_filesystem(data: &[u8]) -> Option<Range<usize>> {
+    if data.len() < 2 * SECTOR as usize || data[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    let mbr_entries = (0..4).map(|i| &data[446 + 16 * i..462 + 16 * i]);
+    let mut sectors = None;
+    for entry in mbr_entries {
+        let word = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap()) as u64;
+        match entry[4] {
+            MBR_TYPE_MOS => {
+                sectors = Some((word(8), word(8) + word(12)));
+                break;
+            }
+            MBR_TYPE_GPT => {
+                sectors = find_gpt_partition(data);
+                break;
+            }
+            _ => {}
+        }
+    }
+    // LBAs come from the disk, so a crafted table may overflow
+    let (first, end) = sectors?;
+    let start = usize::try_from(first.checked_mul(SECTOR)?).ok()?;
+    let end = usize::try_from(end.checked_mul(SECTOR)?)
+        .unwrap_or(usize::MAX)
+        .min(data.len());
+    (start < end).then_some(start..end)
+}
+
+/// First and past-the-end sectors of the MOS partition listed in the primary GPT
+fn find_gpt_partition(data: &[u8]) -> Option<(u64, u64)> {
+    let header = &data[SECTOR as usize..2 * SECTOR as usize];
+    if &header[0..8] != b"EFI PART" {
+        return None;
+    }
+    let word = |bytes: &[u8], i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    let half = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap()) as usize;
+    let entries_start = usize::try_from(word(header, 72).checked_mul(SECTOR)?).ok()?;
+    let (count, size) = (half(80), half(84));
+    if size < GPT_ENTRY_SIZE as usize {
+        return None;
+    }
+    let mos = guid(GPT_TYPE_MOS);
+    let entry = (0..count)
+        .map_while(|i| {
+            let offset = entries_start.checked_add(i.checked_mul(size)?)?;
+            data.get(offset..offset.checked_add(size)?)
+        })
+        .find(|entry| entry[0..16] == mos)?;
+    Some((word(entry, 32), word(entry, 40).checked_add(1)?))
+}
+
+fn mbr_entry(disk: &mut [u8], i: usize, kind: u8, start: u64, sectors: u64) {
+    let entry = &mut disk[446 + 16 * i..462 + 16 * i];
+    // CHS fields at their maximum tell readers to use the LBA fields
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+}
+
+fn gpt_header(
+    lba: u64,
+    other: u64,
+    entries_lba: u64,
+    usable: (u64, u64),
+    entries: &[u8],
+) -> Vec<u8> {
+    let mut header = vec![0; 92];
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&lba.to_le_bytes());
+    header[32..40].copy_from_slice(&other.to_le_bytes());
+    header[40..48].copy_from_slice(&usable.0.to_le_bytes());
+    header[48..56].copy_from_slice(&usable.1.to_le_bytes());
+    header[56..72].copy_from_slice(&guid(GPT_DISK_GUID));
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&(GPT_ENTRY_CNT as u32).to_le_bytes());
+    header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+    let crc = crc32(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// On-disk bytes of a GUID: the first three groups are little-endian
+fn guid(text: &str) -> [u8; 16] {
+    let hex: Vec<u8> = text
+        .split('-')
+        .flat_map(|group| {
+            (0..group.len())
+                .step_by(2)
+                .map(move |i| u8::from_str_radix(&group[i..i + 2], 16).unwrap())
+        })
+        .collect();
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hex);
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
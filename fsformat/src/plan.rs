@@ -0,0 +1,61 @@
+//! The layout a build would produce, as reported by `--dry-run`.
+
+use crate::disk::DiskError;
+use crate::fs::FileType;
+use crate::inspect::{format_line, type_char};
+
+pub struct PlannedFile {
+    pub path: String,
+    pub file_type: FileType,
+    pub size: u32,
+    pub blocks: Vec<u32>,
+    pub index: Vec<u32>,
+}
+
+pub struct Plan {
+    /// Blocks on the disk
+    pub block_cnt: u32,
+    /// Blocks the build allocates, metadata included; may exceed `block_cnt`
+    pub needed: u32,
+    pub files: Vec<PlannedFile>,
+    /// Entries that would stop the build, in the order they were met
+    pub violations: Vec<DiskError>,
+}
+
+impl Plan {
+    pub fn fits(&self) -> bool {
+        self.needed <= self.block_cnt
+    }
+
+    /// Print every file with its planned blocks, then the violations and the totals
+    pub fn print(&self) {
+        for file in &self.files {
+            let kind = type_char(file.file_type).to_string();
+            println!(
+                "{}",
+                format_line(&kind, file.size, &file.blocks, &file.index, &file.path)
+            );
+        }
+        if !self.violations.is_empty() {
+            println!();
+            println!("{} entries cannot be stored:", self.violations.len());
+            for violation in &self.violations {
+                println!("  {}", violation);
+            }
+        }
+        println!();
+        println!(
+            "Needs {} of {} blocks ({:.1}%)",
+            self.needed,
+            self.block_cnt,
+            self.needed as f64 * 100.0 / self.block_cnt as f64
+        );
+        if !self.fits() {
+            println!(
+                "Does not fit: {} blocks short; blocks from {} on are past the end of the disk",
+                self.needed - self.block_cnt,
+                self.block_cnt
+            );
+        }
+    }
+}
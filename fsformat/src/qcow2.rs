@@ -0,0 +1,202 @@
+//! qcow2 disk images.
+//!
+//! The writer produces version 3 images with clusters of one MOS block and 16-bit
+//! refcounts. Only clusters holding non-zero data are stored, and since free blocks
+//! are zeroed, a mostly empty disk takes little more room than its contents. The
+//! layout is header, L1 table, refcount table and blocks, L2 tables, then data.
+//!
+//! The reader accepts versions 2 and 3 without a backing file, encryption or
+//! compressed clusters, which covers what the writer and `qemu-img convert` produce.
+
+use std::io::{Error, ErrorKind, Write};
+
+use crate::fs::BLOCK_SIZE;
+
+pub const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+const CLUSTER_BITS: u32 = BLOCK_SIZE.trailing_zeros();
+const CLUSTER: u64 = 1 << CLUSTER_BITS;
+const HEADER_LEN: usize = 104;
+/// Refcounts are `1 << REFCOUNT_ORDER` bits wide
+const REFCOUNT_ORDER: u32 = 4;
+/// Set in L1 and L2 entries whose cluster has a refcount of exactly one
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Set in version 3 L2 entries whose cluster reads as zeros
+const OFLAG_ZERO: u64 = 1;
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Incompatible feature bits `read` copes with: only "dirty", which leaves the
+/// refcounts stale but the mapping intact
+const INCOMPATIBLE_KNOWN: u64 = 1 << 0;
+/// Largest virtual disk `read` accepts, since the whole disk is decoded into memory
+const MAX_SIZE: u64 = 1 << 32;
+
+/// Whether `data` starts like a qcow2 image
+pub fn is_qcow2(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Write the raw disk `data` as a qcow2 image
+pub fn write<W: Write>(data: &[u8], out: &mut W) -> std::io::Result<()> {
+    let per_table = CLUSTER / 8;
+    let cluster_cnt = (data.len() as u64).div_ceil(CLUSTER);
+    let cluster =
+        |i: u64| &data[(i * CLUSTER) as usize..((i + 1) * CLUSTER).min(data.len() as u64) as usize];
+    let stored: Vec<u64> = (0..cluster_cnt)
+        .filter(|&i| cluster(i).iter().any(|&b| b != 0))
+        .collect();
+    let l1_size = cluster_cnt.div_ceil(per_table);
+    let mut l2_tables: Vec<u64> = stored.iter().map(|&i| i / per_table).collect();
+    l2_tables.dedup();
+
+    // Refcount blocks must cover every cluster, themselves included
+    let l1_clusters = (l1_size * 8).div_ceil(CLUSTER);
+    let fixed = 1 + l1_clusters + l2_tables.len() as u64 + stored.len() as u64;
+    let per_refcount_block = (CLUSTER * 8) >> REFCOUNT_ORDER;
+    let (mut table_clusters, mut block_cnt) = (1, 1);
+    loop {
+        let total = fixed + table_clusters + block_cnt;
+        let needed_blocks = total.div_ceil(per_refcount_block);
+        let needed_table = (needed_blocks * 8).div_ceil(CLUSTER);
+        if (needed_table, needed_blocks) == (table_clusters, block_cnt) {
+            break;
+        }
+        (table_clusters, block_cnt) = (needed_table, needed_blocks);
+    }
+    let total = fixed + table_clusters + block_cnt;
+
+    let l1_offset = CLUSTER;
+    let refcount_table_offset = l1_offset + l1_clusters * CLUSTER;
+    let refcount_blocks_offset = refcount_table_offset + table_clusters * CLUSTER;
+    let l2_offset = refcount_blocks_offset + block_cnt * CLUSTER;
+    let data_offset = l2_offset + l2_tables.len() as u64 * CLUSTER;
+
+    let mut header = vec![0; CLUSTER as usize];
+    let mut put = |at: usize, bytes: &[u8]| header[at..at + bytes.len()].copy_from_slice(bytes);
+    put(0, MAGIC);
+    put(4, &3u32.to_be_bytes());
+    put(20, &CLUSTER_BITS.to_be_bytes());
+    put(24, &(data.len() as u64).to_be_bytes());
+    put(36, &(l1_size as u32).to_be_bytes());
+    put(40, &l1_offset.to_be_bytes());
+    put(48, &refcount_table_offset.to_be_bytes());
+    put(56, &(table_clusters as u32).to_be_bytes());
+    put(96, &REFCOUNT_ORDER.to_be_bytes());
+    put(100, &(HEADER_LEN as u32).to_be_bytes());
+    out.write_all(&header)?;
+
+    let mut l1 = vec![0; (l1_clusters * CLUSTER) as usize];
+    for (n, &table) in l2_tables.iter().enumerate() {
+        let entry = (l2_offset + n as u64 * CLUSTER) | OFLAG_COPIED;
+        l1[table as usize * 8..table as usize * 8 + 8].copy_from_slice(&entry.to_be_bytes());
+    }
+    out.write_all(&l1)?;
+
+    let mut refcount_table = vec![0; (table_clusters * CLUSTER) as usize];
+    for i in 0..block_cnt as usize {
+        let offset = refcount_blocks_offset + i as u64 * CLUSTER;
+        refcount_table[i * 8..i * 8 + 8].copy_from_slice(&offset.to_be_bytes());
+    }
+    out.write_all(&refcount_table)?;
+    let mut refcounts = vec![0; (block_cnt * CLUSTER) as usize];
+    for i in 0..total as usize {
+        refcounts[i * 2..i * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+    out.write_all(&refcounts)?;
+
+    let mut next = data_offset;
+    for clusters in stored.chunk_by(|a, b| a / per_table == b / per_table) {
+        let mut l2 = vec![0; CLUSTER as usize];
+        for &i in clusters {
+            let at = (i % per_table) as usize * 8;
+            l2[at..at + 8].copy_from_slice(&(next | OFLAG_COPIED).to_be_bytes());
+            next += CLUSTER;
+        }
+        out.write_all(&l2)?;
+    }
+    for &i in &stored {
+        let mut contents = cluster(i).to_vec();
+        contents.resize(CLUSTER as usize, 0);
+        out.write_all(&contents)?;
+    }
+    Ok(())
+}
+
+/// Expand a qcow2 image into the raw disk it describes
+pub fn read(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("qcow2: {}", reason));
+    if data.len() < 72 || !is_qcow2(data) {
+        return Err(invalid("bad header"));
+    }
+    let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+    let u64_at = |at: u64| -> std::io::Result<u64> {
+        match usize::try_from(at)
+            .ok()
+            .and_then(|at| data.get(at..at.checked_add(8)?))
+        {
+            Some(bytes) => Ok(u64::from_be_bytes(bytes.try_into().unwrap())),
+            None => Err(invalid("table past the end of the file")),
+        }
+    };
+    let version = u32_at(4);
+    if !matches!(version, 2 | 3) {
+        return Err(invalid("unsupported version"));
+    }
+    if version == 3 && u64_at(72)? & !INCOMPATIBLE_KNOWN != 0 {
+        return Err(invalid("unsupported incompatible features"));
+    }
+    if u64_at(8)? != 0 {
+        return Err(invalid("backing files are not supported"));
+    }
+    if u32_at(32) != 0 {
+        return Err(invalid("encrypted images are not supported"));
+    }
+    let cluster_bits = u32_at(20);
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(invalid("bad cluster size"));
+    }
+    let cluster = 1u64 << cluster_bits;
+    let size = u64_at(24)?;
+    if size > MAX_SIZE {
+        return Err(invalid("disk size too large"));
+    }
+    let l1_size = u32_at(36) as u64;
+    let l1_offset = u64_at(40)?;
+    let per_table = cluster / 8;
+    let tables = size.div_ceil(cluster * per_table);
+    if l1_size < tables {
+        return Err(invalid("L1 table too small for the disk size"));
+    }
+    let past_end = || invalid("table past the end of the file");
+
+    let mut raw = vec![0; size as usize];
+    // L1 entries past the end of the disk are never used
+    for table in 0..tables {
+        let l1_entry = l1_offset.checked_add(table * 8).ok_or_else(past_end)?;
+        let l2_offset = u64_at(l1_entry)? & OFFSET_MASK;
+        if l2_offset == 0 {
+            continue;
+        }
+        for i in 0..per_table {
+            let start = (table * per_table + i) * cluster;
+            if start >= size {
+                break;
+            }
+            let entry = u64_at(l2_offset + i * 8)?;
+            if entry & OFLAG_COMPRESSED != 0 {
+                return Err(invalid("compressed clusters are not supported"));
+            }
+            let offset = (entry & OFFSET_MASK) as usize;
+            if offset == 0 || entry & OFLAG_ZERO != 0 {
+                continue;
+            }
+            let len = cluster.min(size - start) as usize;
+            let contents = offset
+                .checked_add(len)
+                .and_then(|end| data.get(offset..end))
+                .ok_or_else(|| invalid("cluster past the end of the file"))?;
+            raw[start as usize..start as usize + len].copy_from_slice(contents);
+        }
+    }
+    Ok(raw)
+}
@@ -0,0 +1,375 @@
+//! Import from and export to tar archives.
+//!
+//! Reading accepts POSIX ustar, plus the GNU `L`/`K` and pax `x` headers that carry
+//! long names. Regular files, directories, symlinks and hard links are supported;
+//! hard links get their own copy of the data, or share it with `--dedup`. Header mode,
+//! owner and mtime are kept when the image records metadata.
+//!
+//! Writing produces ustar, falling back to a pax header for names that do not fit.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::disk::{split_path, Attributes, DiskError, DiskImage};
+use crate::fs::{File, FileType, MODE_MASK};
+use crate::image::Image;
+
+const BLOCK: usize = 512;
+
+/// Add every entry of the archive `data` to `disk`, relative to the image root.
+/// `path` names the archive in errors.
+pub fn import(disk: &mut DiskImage, data: &[u8], path: &str) -> Result<(), DiskError> {
+    let mut offset = 0;
+    let mut long_name = None;
+    let mut long_link = None;
+    // Image path of every regular file so far, with its data, for hard links
+    let mut files: HashMap<String, &[u8]> = HashMap::new();
+    while offset + BLOCK <= data.len() {
+        let header = &data[offset..offset + BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let start = offset;
+        let error = |reason: String| DiskError::BadArchive {
+            path: path.to_string(),
+            offset: start,
+            reason,
+        };
+        let field = |start: usize, end: usize| {
+            number(&header[start..end]).ok_or_else(|| error("bad numeric field".to_string()))
+        };
+        if field(148, 156)? != checksum(header) {
+            return Err(error("header checksum mismatch".to_string()));
+        }
+        let size = field(124, 136)? as usize;
+        let body = (offset + BLOCK)
+            .checked_add(size)
+            .and_then(|end| data.get(offset + BLOCK..end))
+            .ok_or_else(|| error("archive is truncated".to_string()))?;
+        offset += BLOCK + size.div_ceil(BLOCK) * BLOCK;
+
+        let type_flag = header[156];
+        match type_flag {
+            b'L' => long_name = Some(text(body, path)?),
+            b'K' => long_link = Some(text(body, path)?),
+            b'x' => {
+                for (key, value) in
+                    pax_records(body).ok_or_else(|| error("bad pax header".into()))?
+                {
+                    match key {
+                        "path" => long_name = Some(value.to_string()),
+                        "linkpath" => long_link = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
+            b'g' => {}
+            _ => {
+                let name = match long_name.take() {
+                    Some(name) => name,
+                    None => ustar_name(header, path)?,
+                };
+                let link = match long_link.take() {
+                    Some(link) => link,
+                    None => text(&header[157..257], path)?,
+                };
+                let attributes = Attributes {
+                    mode: field(100, 108)? as u32 & MODE_MASK,
+                    uid: field(108, 116)? as u32,
+                    gid: field(116, 124)? as u32,
+                    mtime: field(136, 148)?.min(u32::MAX as u64) as u32,
+                };
+                let dest = image_path(&name).map_err(error)?;
+                let (dir, entry) = split_path(&dest);
+                let result = match type_flag {
+                    b'0' | 0 | b'7' => {
+                        let Some(entry) = entry else {
+                            return Err(error("a regular file cannot replace the root".into()));
+                        };
+                        files.insert(dest.clone(), body);
+                        disk.create_dir_all(dir)
+                            .and_then(|_| disk.add_file(dir, entry, body))
+                    }
+                    b'1' => {
+                        let target = image_path(&link).map_err(error)?;
+                        let (Some(entry), Some(&body)) = (entry, files.get(&target)) else {
+                            return Err(error(format!("hard link to unknown file '{}'", link)));
+                        };
+                        files.insert(dest.clone(), body);
+                        disk.create_dir_all(dir)
+                            .and_then(|_| disk.add_file(dir, entry, body))
+                    }
+                    b'2' => {
+                        let Some(entry) = entry else {
+                            return Err(error("a symlink cannot replace the root".into()));
+                        };
+                        disk.create_dir_all(dir)
+                            .and_then(|_| disk.add_symlink(dir, entry, &link))
+                    }
+                    b'5' => disk.create_dir_all(&dest),
+                    _ => Err(DiskError::UnsupportedType {
+                        path: format!("{}:{}", path, name),
+                    }),
+                };
+                // A dry run records entries that cannot be stored and carries on
+                let stored = result.is_ok();
+                disk.recover(result)?;
+                if stored {
+                    disk.set_attributes(&dest, attributes)?;
+                }
+            }
+        }
+    }
+    // Archives may end without the two zero blocks, but not in the middle of one
+    match offset < data.len() {
+        true => Err(DiskError::BadArchive {
+            path: path.to_string(),
+            offset,
+            reason: "archive is truncated".to_string(),
+        }),
+        false => Ok(()),
+    }
+}
+
+/// Write the whole tree of `image` as a tar archive, starting with `./` for the root
+pub fn export<W: Write>(image: &Image, out: &mut W) -> std::io::Result<()> {
+    let root = &image.super_block.s_root;
+    write_header(out, "./", b'5', 0, "", &attributes(image, root))?;
+    export_dir(image, root, "", out)?;
+    out.write_all(&[0; 2 * BLOCK])
+}
+
+fn attributes(image: &Image, file: &File) -> Attributes {
+    match image.super_block.has_metadata() {
+        true => Attributes::of(file),
+        false => Attributes::default_for(file.get_type()),
+    }
+}
+
+fn export_dir<W: Write>(image: &Image, dir: &File, path: &str, out: &mut W) -> std::io::Result<()> {
+    for entry in image.read_dir(dir) {
+        let entry_path = format!("{}{}", path, entry.get_name());
+        let attributes = attributes(image, &entry);
+        match entry.get_type() {
+            FileType::Directory => {
+                let dir_path = format!("{}/", entry_path);
+                write_header(out, &dir_path, b'5', 0, "", &attributes)?;
+                export_dir(image, &entry, &dir_path, out)?;
+            }
+            FileType::Symlink => {
+                let target = String::from_utf8_lossy(&image.read_file(&entry)).into_owned();
+                write_header(out, &entry_path, b'2', 0, &target, &attributes)?;
+            }
+            FileType::File => {
+                let data = image.read_file(&entry);
+                write_header(out, &entry_path, b'0', data.len() as u64, "", &attributes)?;
+                out.write_all(&data)?;
+                out.write_all(&vec![0; data.len().next_multiple_of(BLOCK) - data.len()])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(
+    out: &mut W,
+    path: &str,
+    type_flag: u8,
+    size: u64,
+    link: &str,
+    attributes: &Attributes,
+) -> std::io::Result<()> {
+    let split = split_ustar_name(path);
+    let mut records = Vec::new();
+    if split.is_none() {
+        records.push(("path", path));
+    }
+    if link.len() > 100 {
+        records.push(("linkpath", link));
+    }
+    if !records.is_empty() {
+        let body = pax_body(&records);
+        let mut pax = header(
+            "PaxHeader",
+            "",
+            b'x',
+            body.len() as u64,
+            "",
+            &Attributes::default_for(FileType::File),
+        );
+        finish(&mut pax);
+        out.write_all(&pax)?;
+        out.write_all(&body)?;
+        out.write_all(&vec![0; body.len().next_multiple_of(BLOCK) - body.len()])?;
+    }
+
+    let (prefix, name) = split.unwrap_or(("", truncate(path, 100)));
+    let mut block = header(
+        name,
+        prefix,
+        type_flag,
+        size,
+        truncate(link, 100),
+        attributes,
+    );
+    finish(&mut block);
+    out.write_all(&block)
+}
+
+fn header(
+    name: &str,
+    prefix: &str,
+    type_flag: u8,
+    size: u64,
+    link: &str,
+    attributes: &Attributes,
+) -> [u8; BLOCK] {
+    let mut block = [0; BLOCK];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    put_number(&mut block[100..108], attributes.mode as u64);
+    put_number(&mut block[108..116], attributes.uid as u64);
+    put_number(&mut block[116..124], attributes.gid as u64);
+    put_number(&mut block[124..136], size);
+    put_number(&mut block[136..148], attributes.mtime as u64);
+    block[156] = type_flag;
+    block[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    block
+}
+
+/// Fill in the checksum field of a finished header
+fn finish(block: &mut [u8; BLOCK]) {
+    let sum = format!("{:06o}\0 ", checksum(block));
+    block[148..156].copy_from_slice(sum.as_bytes());
+}
+
+/// Header checksum, counting the checksum field itself as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| match i {
+            148..156 => b' ' as u64,
+            _ => b as u64,
+        })
+        .sum()
+}
+
+/// Write an octal field, or base-256 when the value does not fit
+fn put_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < 1 << (3 * digits) {
+        let text = format!("{:0width$o}\0", value, width = digits);
+        field.copy_from_slice(text.as_bytes());
+    } else {
+        let bytes = value.to_be_bytes();
+        field.fill(0);
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&bytes);
+        field[0] |= 0x80;
+    }
+}
+
+/// Parse an octal field, or a GNU base-256 one
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold((field[0] & 0x7f) as u64, |n, &b| {
+                n.checked_mul(256)?.checked_add(b as u64)
+            });
+    }
+    let text = std::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    match text {
+        "" => Some(0),
+        _ => u64::from_str_radix(text, 8).ok(),
+    }
+}
+
+fn text(field: &[u8], path: &str) -> Result<String, DiskError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8(field[..len].to_vec()).map_err(|_| DiskError::NonUtf8Name {
+        path: format!("{}:{}", path, String::from_utf8_lossy(&field[..len])),
+    })
+}
+
+/// Name of an entry, joined with the ustar prefix field
+fn ustar_name(header: &[u8], path: &str) -> Result<String, DiskError> {
+    let name = text(&header[..100], path)?;
+    // GNU tar puts other data where the prefix would be and marks it with "ustar  "
+    if &header[257..263] != b"ustar\0" {
+        return Ok(name);
+    }
+    match text(&header[345..500], path)? {
+        prefix if prefix.is_empty() => Ok(name),
+        prefix => Ok(format!("{}/{}", prefix, name)),
+    }
+}
+
+/// Split a path into ustar prefix and name fields, if it fits
+fn split_ustar_name(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    // A trailing slash belongs to the name
+    let (i, _) = path
+        .strip_suffix('/')
+        .unwrap_or(path)
+        .match_indices('/')
+        .find(|&(i, _)| i <= 155 && path.len() - i - 1 <= 100)?;
+    Some((&path[..i], &path[i + 1..]))
+}
+
+fn truncate(s: &str, len: usize) -> &str {
+    let mut end = s.len().min(len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Records of a pax extended header, each `"<len> <key>=<value>\n"`
+fn pax_records(body: &[u8]) -> Option<Vec<(&str, &str)>> {
+    let mut records = Vec::new();
+    let mut rest = std::str::from_utf8(body).ok()?;
+    while !rest.is_empty() && !rest.starts_with('\0') {
+        let (len, _) = rest.split_once(' ')?;
+        let record = rest.get(..len.parse().ok()?)?;
+        rest = &rest[record.len()..];
+        let (key, value) = record
+            .get(len.len() + 1..)?
+            .strip_suffix('\n')?
+            .split_once('=')?;
+        records.push((key, value));
+    }
+    Some(records)
+}
+
+fn pax_body(records: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = String::new();
+    for (key, value) in records {
+        // The length prefix counts its own digits
+        let base = key.len() + value.len() + 3;
+        let mut len = base + 1;
+        while len != base + len.to_string().len() {
+            len = base + len.to_string().len();
+        }
+        body.push_str(&format!("{} {}={}\n", len, key, value));
+    }
+    body.into_bytes()
+}
+
+/// Turn an archive member name into an absolute image path, `/` for the root
+fn image_path(name: &str) -> Result<String, String> {
+    let names: Vec<&str> = name
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect();
+    if names.contains(&"..") {
+        return Err(format!("'{}' contains '..'", name));
+    }
+    Ok(format!("/{}", names.join("/")))
+}
@@ -0,0 +1,168 @@
+//! Space accounting for an image, like `df` and `du`.
+
+use std::collections::HashSet;
+
+use crate::disk::join_path;
+use crate::fs::{BlockType, File, FileType, BLOCK_SIZE};
+use crate::image::Image;
+
+/// Types in the order they are reported
+const TYPES: [BlockType; 8] = [
+    BlockType::Boot,
+    BlockType::Super,
+    BlockType::BMap,
+    BlockType::Checksum,
+    BlockType::File,
+    BlockType::Index,
+    BlockType::Data,
+    BlockType::Free,
+];
+
+pub struct Usage {
+    pub block_cnt: u32,
+    counts: [u32; TYPES.len()],
+    /// Blocks marked used in the bitmap that no file refers to
+    pub unreferenced: u32,
+    /// Further references to blocks already counted, which deduplicated files share
+    pub shared: u32,
+    /// Recursive block count of every directory, children before their parent as `du` prints them
+    pub dirs: Vec<(String, u32)>,
+}
+
+impl Usage {
+    /// Count the blocks of `image` by walking the tree from `s_root`.
+    /// Free blocks come from the bitmap.
+    pub fn of(image: &Image) -> Usage {
+        let block_cnt = image.super_block.get_block_cnt().min(image.block_count());
+        let mut usage = Usage {
+            block_cnt,
+            counts: [0; TYPES.len()],
+            unreferenced: 0,
+            shared: 0,
+            dirs: Vec::new(),
+        };
+        usage.add(BlockType::Boot, image.super_block.get_boot_cnt());
+        usage.add(BlockType::Super, 1);
+        usage.add(BlockType::BMap, image.bitmap_block_cnt());
+        if let Some(blocks) = image.super_block.checksum_blocks() {
+            usage.add(BlockType::Checksum, blocks.len() as u32);
+        }
+        let free = (0..block_cnt).filter(|&n| image.is_free(n)).count() as u32;
+        usage.add(BlockType::Free, free);
+        usage.walk(image, &image.super_block.s_root, "/", &mut HashSet::new());
+        let counted: u32 = usage.counts.iter().sum();
+        usage.unreferenced = block_cnt.saturating_sub(counted);
+        usage
+    }
+
+    /// Number of blocks of type `block_type`
+    pub fn count(&self, block_type: BlockType) -> u32 {
+        self.counts[TYPES.iter().position(|&t| t == block_type).unwrap()]
+    }
+
+    pub fn used(&self) -> u32 {
+        self.block_cnt - self.count(BlockType::Free)
+    }
+
+    /// Used blocks as a percentage of the disk
+    pub fn fill(&self) -> f64 {
+        self.used() as f64 * 100.0 / self.block_cnt as f64
+    }
+
+    /// Print the per-type block counts and the free space
+    pub fn print_summary(&self) {
+        println!("{:<13} {:>10} {:>10}", "Type", "Blocks", "KiB");
+        for block_type in TYPES {
+            // Most images have no checksum table
+            if block_type == BlockType::Checksum && self.count(block_type) == 0 {
+                continue;
+            }
+            print_row(type_name(block_type), self.count(block_type));
+        }
+        if self.unreferenced != 0 {
+            print_row("Unreferenced", self.unreferenced);
+        }
+        println!(
+            "{:<13} {:>10} {:>10}  {:.1}% used",
+            "Total",
+            self.block_cnt,
+            kib(self.block_cnt),
+            self.fill()
+        );
+        if self.shared != 0 {
+            println!(
+                "Dedup saved {} blocks ({} KiB)",
+                self.shared,
+                kib(self.shared)
+            );
+        }
+    }
+
+    /// Print the recursive usage of every directory
+    pub fn print_dirs(&self) {
+        for (path, blocks) in &self.dirs {
+            println!("{:>10}  {}", kib(*blocks), path);
+        }
+    }
+
+    fn add(&mut self, block_type: BlockType, n: u32) {
+        self.counts[TYPES.iter().position(|&t| t == block_type).unwrap()] += n;
+    }
+
+    /// Count the blocks of `file` and everything below it, returning the total.
+    /// Blocks in `seen` were counted for an earlier file sharing them.
+    fn walk(&mut self, image: &Image, file: &File, path: &str, seen: &mut HashSet<u32>) -> u32 {
+        let data_type = match file.get_type() {
+            FileType::Directory => BlockType::File,
+            _ => BlockType::Data,
+        };
+        let mut new_blocks = |blocks: Vec<u32>| {
+            let total = blocks.len() as u32;
+            let new = blocks.into_iter().filter(|&n| seen.insert(n)).count() as u32;
+            (new, total - new)
+        };
+        let (data, shared_data) = new_blocks(
+            (0..file.get_size().div_ceil(BLOCK_SIZE))
+                .map(|i| image.file_block(file, i))
+                .filter(|&n| n != 0)
+                .collect(),
+        );
+        let (index, shared_index) = new_blocks(image.index_blocks(file));
+        self.add(data_type, data);
+        self.add(BlockType::Index, index);
+
+        let mut total = data + index;
+        if file.get_type() != FileType::Directory {
+            self.shared += shared_data + shared_index;
+        } else if shared_data == 0 {
+            // Directories never share blocks; one seen before would make the walk loop
+            for entry in image.read_dir(file) {
+                let entry_path = join_path(path, &entry.get_name());
+                total += self.walk(image, &entry, &entry_path, seen);
+            }
+            self.dirs.push((path.to_string(), total));
+        }
+        total
+    }
+}
+
+fn print_row(name: &str, blocks: u32) {
+    println!("{:<13} {:>10} {:>10}", name, blocks, kib(blocks));
+}
+
+fn kib(blocks: u32) -> u64 {
+    blocks as u64 * BLOCK_SIZE as u64 / 1024
+}
+
+fn type_name(block_type: BlockType) -> &'static str {
+    match block_type {
+        BlockType::Free => "Free",
+        BlockType::Boot => "Boot",
+        BlockType::BMap => "BMap",
+        BlockType::Super => "Super",
+        BlockType::Data => "Data",
+        BlockType::File => "File",
+        BlockType::Index => "Index",
+        BlockType::Checksum => "Checksum",
+    }
+}
@@ -0,0 +1,119 @@
+//! Compare every used block with the checksum table of an image.
+//!
+//! Corrupted blocks are reported with the file that owns them, so a bad block
+//! found after a kernel test points straight at the file that was damaged.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::crc::crc32;
+use crate::disk::join_path;
+use crate::fs::{File, FileType, BLOCK_SIZE, CRC_PER_BLOCK};
+use crate::image::Image;
+
+pub struct Corruption {
+    pub block: u32,
+    /// Paths of the files using the block, several when deduplicated files share it,
+    /// or a description such as `<bitmap>`
+    pub owners: Vec<String>,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+/// Every used block whose CRC does not match the table, in block order.
+/// Fails if the image has no usable checksum table.
+pub fn verify(image: &Image) -> Result<Vec<Corruption>, &'static str> {
+    let table = image
+        .super_block
+        .checksum_blocks()
+        .ok_or("the image has no checksum table")?;
+    if table.end > image.block_count() {
+        return Err("the checksum table is past the end of the image");
+    }
+    let block_cnt = image
+        .super_block
+        .get_block_cnt()
+        .min(image.block_count())
+        .min(table.len() as u32 * CRC_PER_BLOCK);
+
+    let mut owners = vec![Vec::new(); block_cnt as usize];
+    let boot_cnt = image.super_block.get_boot_cnt();
+    let bitmap_end = image.super_block.bitmap_start() + image.bitmap_block_cnt();
+    for (n, owner) in owners.iter_mut().enumerate() {
+        let n = n as u32;
+        *owner = match n {
+            n if n < boot_cnt => vec!["<boot>".to_string()],
+            n if n == boot_cnt => vec!["<superblock>".to_string()],
+            n if n < bitmap_end => vec!["<bitmap>".to_string()],
+            _ => Vec::new(),
+        };
+    }
+    record(
+        image,
+        &image.super_block.s_root,
+        "/",
+        &mut owners,
+        &mut HashSet::new(),
+    );
+
+    let mut corruptions = Vec::new();
+    for n in 0..block_cnt {
+        if table.contains(&n) || image.is_free(n) {
+            continue;
+        }
+        let expected = image.read_u32(table.start + n / CRC_PER_BLOCK, n % CRC_PER_BLOCK);
+        let actual = crc32(image.block(n));
+        if actual != expected {
+            corruptions.push(Corruption {
+                block: n,
+                owners: match &owners[n as usize] {
+                    paths if paths.is_empty() => vec!["<unreferenced>".to_string()],
+                    paths => paths.clone(),
+                },
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(corruptions)
+}
+
+/// Note `path` as an owner of the blocks of `file`, and recurse into directories
+/// whose blocks are not in `visited` yet
+fn record(
+    image: &Image,
+    file: &File,
+    path: &str,
+    owners: &mut [Vec<String>],
+    visited: &mut HashSet<u32>,
+) {
+    let mut blocks = image.index_blocks(file);
+    blocks.extend((0..file.get_size().div_ceil(BLOCK_SIZE)).map(|i| image.file_block(file, i)));
+    for n in blocks {
+        if let Some(paths) = owners.get_mut(n as usize) {
+            // A hole reads as block 0, which the boot area already owns
+            if n != 0 && !paths.iter().any(|owner| owner == path) {
+                paths.push(path.to_string());
+            }
+        }
+    }
+    if file.get_type() == FileType::Directory && image.visit_dir(file, visited) {
+        for entry in image.read_dir(file) {
+            let entry_path = join_path(path, &entry.get_name());
+            record(image, &entry, &entry_path, owners, visited);
+        }
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block {}: crc {:08x}, expected {:08x}, owned by '{}'",
+            self.block,
+            self.actual,
+            self.expected,
+            self.owners.join("', '")
+        )
+    }
+}
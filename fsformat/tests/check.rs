@@ -0,0 +1,106 @@
+use fsformat::check::Checker;
+use fsformat::fs::{BLOCK_SIZE, BLOCK_SIZE_BIT, FILE_STRUCT_SIZE};
+use fsformat::{DiskImage, Image};
+
+fn sample() -> Image {
+    let mut disk = DiskImage::new(256);
+    disk.create_dir_all("/etc").unwrap();
+    disk.add_file("/etc", "motd", b"hello\n").unwrap();
+    disk.add_file("/", "data", &[3u8; 10_000]).unwrap();
+    disk.to_image()
+}
+
+/// Byte offset of the `File` record named `name` in the root directory
+fn record_offset(image: &Image, name: &str) -> usize {
+    let block = image.super_block.s_root.get_direct(0);
+    let slot = image
+        .file_slots(block)
+        .position(|slot| slot.starts_with(name.as_bytes()) && slot[name.len()] == 0)
+        .unwrap();
+    (block * BLOCK_SIZE) as usize + slot * FILE_STRUCT_SIZE as usize
+}
+
+/// `image` with the four bytes at `at` replaced by `value`
+fn patched(image: &Image, at: usize, value: u32) -> Image {
+    let mut bytes = image.as_bytes().to_vec();
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    Image::from_bytes(bytes).unwrap()
+}
+
+/// `image` with the bitmap bit of block `n` flipped
+fn flipped(image: &Image, n: u32) -> Image {
+    let mut bytes = image.as_bytes().to_vec();
+    let block = image.super_block.bitmap_start() + n / BLOCK_SIZE_BIT;
+    let at = (block * BLOCK_SIZE) as usize + (n % BLOCK_SIZE_BIT / 8) as usize;
+    bytes[at] ^= 1 << (n % 8);
+    Image::from_bytes(bytes).unwrap()
+}
+
+fn assert_finds(image: &Image, expected: &str) {
+    let problems = Checker::new(image).check();
+    assert!(
+        problems.iter().any(|p| p.contains(expected)),
+        "no '{}' in {:?}",
+        expected,
+        problems
+    );
+}
+
+#[test]
+fn check_passes_a_fresh_image() {
+    let problems = Checker::new(&sample()).check();
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn check_reports_a_bad_magic() {
+    let image = sample();
+    let at = (image.super_block_no() * BLOCK_SIZE) as usize;
+    let problems = Checker::new(&patched(&image, at, 0xdeadbeef)).check();
+    assert_eq!(problems.len(), 1, "{:?}", problems);
+    assert!(problems[0].starts_with("bad magic 0xdeadbeef"));
+}
+
+#[test]
+fn check_reports_bitmap_mismatches() {
+    let image = sample();
+    let data = image.lookup("/data").unwrap().get_direct(0);
+    assert_finds(
+        &flipped(&image, data),
+        &format!("block {}: marked free but used by '/data'", data),
+    );
+    let free = (0..image.block_count())
+        .find(|&n| image.is_free(n))
+        .unwrap();
+    assert_finds(
+        &flipped(&image, free),
+        &format!("block {}: marked used but not referenced", free),
+    );
+}
+
+#[test]
+fn check_reports_bad_pointers_and_sizes() {
+    let image = sample();
+    let direct = record_offset(&image, "data") + 136;
+    assert_finds(
+        &patched(&image, direct + 4, 5000),
+        "'/data': pointer to block 5000 past the end of the disk (256 blocks)",
+    );
+    let size = record_offset(&image, "data") + 128;
+    assert_finds(
+        &patched(&image, size, 20_000),
+        "'/data': f_size 20000 needs 5 blocks but 3 are allocated",
+    );
+}
+
+#[test]
+fn check_reports_a_block_used_twice() {
+    let image = sample();
+    let root = image.super_block.s_root.get_direct(0);
+    // /etc now lists the root directory as its own contents
+    let etc = record_offset(&image, "etc") + 136;
+    assert_finds(
+        &patched(&image, etc, root),
+        &format!("block {}: used by both '/' and '/etc'", root),
+    );
+}
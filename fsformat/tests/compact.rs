@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+
+use fsformat::check::Checker;
+use fsformat::{extract, DiskImage, Image};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fsformat-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A tree with a file big enough for an indirect block, then a few entries deleted
+/// so the free space is spread through the image
+fn fragmented() -> Image {
+    let mut disk = DiskImage::new(1024);
+    disk.create_dir_all("/a/b").unwrap();
+    for i in 0..40 {
+        let data = vec![i as u8; 1000 * i];
+        disk.add_file("/a", &format!("f{}", i), &data).unwrap();
+    }
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+    disk.add_file("/a/b", "big", &big).unwrap();
+    disk.add_file("/", "tail", b"last\n").unwrap();
+    let mut disk = DiskImage::from_image(&disk.to_image()).unwrap();
+    for i in (0..40).step_by(3) {
+        disk.remove(&format!("/a/f{}", i), false).unwrap();
+    }
+    disk.to_image()
+}
+
+/// Every host file under `dir`, relative to it, with its contents
+fn host_tree(dir: &PathBuf) -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.clone()];
+    while let Some(path) = pending.pop() {
+        for entry in std::fs::read_dir(&path).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                let name = path.strip_prefix(dir).unwrap().display().to_string();
+                files.push((name, std::fs::read(&path).unwrap()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn compact_keeps_the_tree_and_passes_check() {
+    let original = fragmented();
+    assert!(Checker::new(&original).check().is_empty());
+    let dir = scratch_dir("compact");
+    extract::extract(&original, dir.join("original").to_str().unwrap()).unwrap();
+    let expected = host_tree(&dir.join("original"));
+
+    for shrink in [false, true] {
+        let compacted = DiskImage::compact(&original, shrink).unwrap().to_image();
+        let problems = Checker::new(&compacted).check();
+        assert!(problems.is_empty(), "shrink {}: {:?}", shrink, problems);
+        if shrink {
+            assert!(compacted.block_count() < original.block_count());
+        } else {
+            assert_eq!(compacted.block_count(), original.block_count());
+        }
+        let out = dir.join(format!("shrink-{}", shrink));
+        extract::extract(&compacted, out.to_str().unwrap()).unwrap();
+        assert!(host_tree(&out) == expected, "shrink {}", shrink);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn compact_leaves_free_space_in_one_run() {
+    let in_one_run = |image: &Image| {
+        let first_free = (0..image.block_count())
+            .find(|&n| image.is_free(n))
+            .unwrap();
+        (first_free..image.block_count()).all(|n| image.is_free(n))
+    };
+    let original = fragmented();
+    assert!(!in_one_run(&original));
+    assert!(in_one_run(
+        &DiskImage::compact(&original, false).unwrap().to_image()
+    ));
+}
@@ -0,0 +1,99 @@
+use fsformat::check::Checker;
+use fsformat::fs::{BLOCK_SIZE, FILE_STRUCT_SIZE};
+use fsformat::{DiskImage, Image};
+
+/// Two copies of a file with an indirect block, and one differing in its last byte
+fn sample(dedup: bool) -> Image {
+    let data: Vec<u8> = (0..60_000u32).map(|i| (i % 241) as u8).collect();
+    let mut other = data.clone();
+    *other.last_mut().unwrap() ^= 1;
+    let mut disk = DiskImage::new(256);
+    disk.set_dedup(dedup);
+    disk.add_file("/", "a", &data).unwrap();
+    disk.add_file("/", "b", &data).unwrap();
+    disk.add_file("/", "c", &other).unwrap();
+    disk.to_image()
+}
+
+/// Byte offset of the `File` record named `name` in the root directory
+fn record_offset(image: &Image, name: &str) -> usize {
+    let block = image.super_block.s_root.get_direct(0);
+    let slot = image
+        .file_slots(block)
+        .position(|slot| slot.starts_with(name.as_bytes()) && slot[name.len()] == 0)
+        .unwrap();
+    (block * BLOCK_SIZE) as usize + slot * FILE_STRUCT_SIZE as usize
+}
+
+/// `image` with direct pointer `i` of `name` set to `block`
+fn with_direct(image: &Image, name: &str, i: u32, block: u32) -> Image {
+    let mut bytes = image.as_bytes().to_vec();
+    let at = record_offset(image, name) + 136 + 4 * i as usize;
+    bytes[at..at + 4].copy_from_slice(&block.to_le_bytes());
+    Image::from_bytes(bytes).unwrap()
+}
+
+#[test]
+fn identical_files_share_their_blocks() {
+    let plain = sample(false);
+    let image = sample(true);
+    let (a, b, c) = (
+        image.lookup("/a").unwrap(),
+        image.lookup("/b").unwrap(),
+        image.lookup("/c").unwrap(),
+    );
+    assert!(a.pointers() == b.pointers());
+    assert!(a.pointers() != c.pointers());
+    assert!(image.read_file(&a) == image.read_file(&b));
+    assert!(image.read_file(&c) == plain.read_file(&plain.lookup("/c").unwrap()));
+    let free = |image: &Image| {
+        (0..image.block_count())
+            .filter(|&n| image.is_free(n))
+            .count()
+    };
+    assert!(free(&image) > free(&plain));
+    let problems = Checker::new(&image).check();
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn removing_one_copy_keeps_the_other() {
+    let image = sample(true);
+    let data = image.read_file(&image.lookup("/a").unwrap());
+    let mut disk = DiskImage::from_image(&image).unwrap();
+    disk.remove("/a", false).unwrap();
+    let image = disk.to_image();
+    assert!(image.read_file(&image.lookup("/b").unwrap()) == data);
+    let problems = Checker::new(&image).check();
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+
+#[test]
+fn check_flags_a_partial_share() {
+    let image = sample(true);
+    let a = image.lookup("/a").unwrap();
+    // c now shares a's first block, but not the rest
+    let image = with_direct(&image, "c", 0, a.get_direct(0));
+    let problems = Checker::new(&image).check();
+    let expected = format!("block {}: used by both", a.get_direct(0));
+    assert!(
+        problems.iter().any(|p| p.starts_with(&expected)),
+        "{:?}",
+        problems
+    );
+}
+
+#[test]
+fn check_flags_sharing_without_the_feature() {
+    let mut image = sample(false);
+    let a = image.lookup("/a").unwrap();
+    for i in 0..10 {
+        image = with_direct(&image, "b", i, a.get_direct(i));
+    }
+    let problems = Checker::new(&image).check();
+    assert!(
+        problems.iter().any(|p| p.contains("used by both")),
+        "{:?}",
+        problems
+    );
+}
@@ -0,0 +1,93 @@
+use fsformat::check::Checker;
+use fsformat::{DiskError, DiskImage, Image};
+
+fn sample() -> DiskImage {
+    let mut disk = DiskImage::new(256);
+    disk.create_dir_all("/etc/conf.d").unwrap();
+    disk.add_file("/etc", "motd", b"hello\n").unwrap();
+    disk.add_file("/etc/conf.d", "net", &[7u8; 50_000]).unwrap();
+    disk.add_file("/", "old", b"old\n").unwrap();
+    disk
+}
+
+fn free_blocks(image: &Image) -> usize {
+    (0..image.block_count())
+        .filter(|&n| image.is_free(n))
+        .count()
+}
+
+fn assert_clean(disk: &mut DiskImage) -> Image {
+    let image = disk.to_image();
+    let problems = Checker::new(&image).check();
+    assert!(problems.is_empty(), "{:?}", problems);
+    image
+}
+
+#[test]
+fn remove_frees_every_block() {
+    let mut empty = DiskImage::new(256);
+    empty.create_dir_all("/etc").unwrap();
+    let before = free_blocks(&empty.to_image());
+
+    let mut disk = sample();
+    assert!(matches!(
+        disk.remove("/etc/conf.d", false),
+        Err(DiskError::DirectoryNotEmpty { .. })
+    ));
+    disk.remove("/etc/conf.d", true).unwrap();
+    disk.remove("/etc/motd", false).unwrap();
+    disk.remove("/old", false).unwrap();
+    let image = assert_clean(&mut disk);
+    assert!(image.lookup("/etc/motd").is_err());
+    assert_eq!(free_blocks(&image), before);
+    assert!(matches!(
+        disk.remove("/old", false),
+        Err(DiskError::NotFound { .. })
+    ));
+}
+
+#[test]
+fn rename_moves_and_replaces_entries() {
+    let mut disk = sample();
+    // Into a directory, keeping the name
+    disk.rename("/old", "/etc").unwrap();
+    // Over an existing regular file, which is replaced
+    disk.rename("/etc/old", "/etc/motd").unwrap();
+    // A whole directory, under a new name
+    disk.rename("/etc/conf.d", "/conf").unwrap();
+    let image = assert_clean(&mut disk);
+
+    assert!(image.lookup("/old").is_err());
+    assert!(image.lookup("/etc/old").is_err());
+    assert!(image.lookup("/etc/conf.d").is_err());
+    let motd = image.lookup("/etc/motd").unwrap();
+    assert_eq!(image.read_file(&motd), b"old\n");
+    let net = image.lookup("/conf/net").unwrap();
+    assert!(image.read_file(&net) == [7u8; 50_000]);
+}
+
+#[test]
+fn rename_rejects_bad_destinations() {
+    let mut disk = sample();
+    assert!(matches!(
+        disk.rename("/etc", "/etc/conf.d/etc"),
+        Err(DiskError::BadPath { .. })
+    ));
+    disk.create_dir_all("/conf/conf.d").unwrap();
+    assert!(matches!(
+        disk.rename("/etc/conf.d", "/conf"),
+        Err(DiskError::AlreadyExists { .. })
+    ));
+    assert!(matches!(
+        disk.rename("/etc/motd", "/conf/conf.d/.."),
+        Err(DiskError::BadPath { .. })
+    ));
+    assert!(matches!(
+        disk.rename("/missing", "/etc"),
+        Err(DiskError::NotFound { .. })
+    ));
+    // Nothing moved
+    let image = assert_clean(&mut disk);
+    assert!(image.lookup("/etc/conf.d/net").is_ok());
+    assert!(image.lookup("/etc/motd").is_ok());
+}
@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+use fsformat::DiskImage;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fsformat-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Create the same tree under `root`, writing entries in the given name order
+fn make_tree(root: &Path, names: &[&str]) {
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    for name in names {
+        std::fs::write(root.join(name), name.repeat(3000)).unwrap();
+        std::fs::write(root.join("sub").join(name), name.as_bytes()).unwrap();
+    }
+}
+
+fn build(root: &Path) -> Vec<u8> {
+    let mut disk = DiskImage::new(1024);
+    disk.add_path_as("/", "root", root.to_str().unwrap())
+        .unwrap();
+    disk.add_file("/", "motd", b"hello\n").unwrap();
+    let mut bytes = Vec::new();
+    disk.write_to(&mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn same_tree_builds_identical_images() {
+    let dir = scratch_dir("reproducible");
+    let names = ["delta", "alpha", "charlie", "bravo", "echo"];
+    let reversed: Vec<&str> = names.iter().rev().copied().collect();
+    make_tree(&dir.join("one"), &names);
+    make_tree(&dir.join("two"), &reversed);
+
+    let first = build(&dir.join("one"));
+    assert_eq!(first.len(), 1024 * 4096);
+    assert!(first == build(&dir.join("one")));
+    assert!(first == build(&dir.join("two")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,79 @@
+use fsformat::{tar, DiskImage, Image};
+
+/// Over 100 bytes, so it needs the ustar prefix, and ending in a multi-byte character
+const LONG_DIR: &str =
+    "/u/ddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddddd";
+const LONG_NAME: &str = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffé";
+
+fn image(disk: &mut DiskImage) -> Image {
+    let mut bytes = Vec::new();
+    disk.write_to(&mut bytes).unwrap();
+    Image::from_bytes(bytes).unwrap()
+}
+
+#[test]
+fn tar_round_trip_keeps_every_entry() {
+    let mut disk = DiskImage::new(1024);
+    disk.create_dir_all("/etc").unwrap();
+    disk.add_file("/etc", "motd", b"hello\n").unwrap();
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    disk.add_file("/", "big", &data).unwrap();
+    disk.add_symlink("/", "link", "etc/motd").unwrap();
+    disk.create_dir_all(LONG_DIR).unwrap();
+    disk.add_file(LONG_DIR, LONG_NAME, "é".as_bytes()).unwrap();
+    let original = image(&mut disk);
+
+    let mut archive = Vec::new();
+    tar::export(&original, &mut archive).unwrap();
+    let mut copy = DiskImage::new(1024);
+    tar::import(&mut copy, &archive, "test.tar").unwrap();
+    let copy = image(&mut copy);
+
+    let long_path = format!("{}/{}", LONG_DIR, LONG_NAME);
+    for path in ["/etc/motd", "/big", "/link", long_path.as_str()] {
+        let a = original.lookup(path).unwrap();
+        let b = copy.lookup(path).unwrap();
+        assert!(a.get_type() == b.get_type(), "{}", path);
+        assert!(original.read_file(&a) == copy.read_file(&b), "{}", path);
+    }
+}
+
+/// A ustar header with a valid checksum
+fn header(name: &str, type_flag: u8, size: &[u8]) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..124 + size.len()].copy_from_slice(size);
+    header[156] = type_flag;
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    header
+}
+
+#[test]
+fn tar_rejects_a_huge_base_256_size() {
+    // u64::MAX, which overflows when added to the offset
+    let mut size = [0u8; 12];
+    size[0] = 0x80;
+    size[4..].fill(0xff);
+    let archive = header("file", b'0', &size);
+    let mut disk = DiskImage::new(64);
+    assert!(tar::import(&mut disk, &archive, "test.tar").is_err());
+}
+
+#[test]
+fn tar_rejects_a_pax_record_shorter_than_its_length() {
+    for record in ["1 x=\n", "0 x=\n"] {
+        let mut archive = header("pax", b'x', format!("{:011o}", record.len()).as_bytes()).to_vec();
+        let mut body = [0u8; 512];
+        body[..record.len()].copy_from_slice(record.as_bytes());
+        archive.extend_from_slice(&body);
+        archive.extend_from_slice(&header("file", b'0', b"00000000000"));
+        let mut disk = DiskImage::new(64);
+        assert!(
+            tar::import(&mut disk, &archive, "test.tar").is_err(),
+            "{}",
+            record
+        );
+    }
+}